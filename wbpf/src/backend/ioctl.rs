@@ -0,0 +1,240 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::Read,
+  os::unix::prelude::AsRawFd,
+  path::Path,
+};
+
+use anyhow::Result;
+use memmap2::{MmapOptions, MmapRaw};
+use nix::fcntl;
+use tokio::{io::unix::AsyncFd, sync::Mutex};
+
+use crate::{
+  dm::DATA_MEMORY_SIZE,
+  exception::ExceptionState,
+  perf::PerfCounters,
+  uapi::{
+    ioc_get_num_pe, ioc_load_code, ioc_start, ioc_stop, wbpf_uapi_load_code_args, wbpf_uapi_num_pe,
+    wbpf_uapi_pe_exception_state, wbpf_uapi_performance_counters, wbpf_uapi_read_dm_args,
+    wbpf_uapi_read_performance_counters_args, wbpf_uapi_start_args, wbpf_uapi_stop_args,
+    wbpf_uapi_write_dm_args,
+  },
+};
+
+use super::Backend;
+
+/// Backend driving a `/dev/wbpf*` node through the kernel driver's ioctl interface.
+pub struct IoctlBackend {
+  file: Mutex<AsyncFd<File>>,
+  file_fd: i32,
+  mem: MmapRaw,
+  num_pe: u32,
+}
+
+impl IoctlBackend {
+  pub fn open(path: &Path) -> Result<Self> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_fd = file.as_raw_fd();
+    fcntl::fcntl(
+      file_fd,
+      fcntl::F_SETFL(fcntl::OFlag::O_CLOEXEC | fcntl::OFlag::O_NONBLOCK),
+    )?;
+    let mem = MmapOptions::new().len(DATA_MEMORY_SIZE).map_raw(&file)?;
+    let mut rsp: wbpf_uapi_num_pe = Default::default();
+    unsafe {
+      ioc_get_num_pe(file_fd, &mut rsp)?;
+    }
+    Ok(Self {
+      file: Mutex::new(AsyncFd::new(file)?),
+      file_fd,
+      mem,
+      num_pe: rsp.num_pe,
+    })
+  }
+
+  fn unaligned_op(
+    &self,
+    mut offset: usize,
+    mut len: usize,
+    mut op: impl FnMut(usize, bool) -> Result<()>,
+  ) -> Result<()> {
+    let end = offset.checked_add(len);
+    if end.is_none() {
+      anyhow::bail!("bad length");
+    }
+    while offset % 4 != 0 && len > 0 {
+      op(offset, false)?;
+      offset += 1;
+      len -= 1;
+    }
+    while len >= 4 {
+      op(offset, true)?;
+      offset += 4;
+      len -= 4;
+    }
+    while len > 0 {
+      op(offset, false)?;
+      offset += 1;
+      len -= 1;
+    }
+
+    Ok(())
+  }
+}
+
+impl Backend for IoctlBackend {
+  fn num_pe(&self) -> Result<u32> {
+    Ok(self.num_pe)
+  }
+
+  fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()> {
+    let args = wbpf_uapi_load_code_args {
+      pe_index,
+      offset,
+      code: code.as_ptr(),
+      code_len: code.len() as u32,
+    };
+    unsafe {
+      ioc_load_code(self.file_fd, &args)?;
+    }
+    Ok(())
+  }
+
+  fn start(&self, pe_index: u32, pc: u32) -> Result<()> {
+    let args = wbpf_uapi_start_args { pe_index, pc };
+    unsafe {
+      ioc_start(self.file_fd, &args)?;
+    }
+    Ok(())
+  }
+
+  fn stop(&self, pe_index: u32) -> Result<()> {
+    let args = wbpf_uapi_stop_args { pe_index };
+    unsafe {
+      ioc_stop(self.file_fd, &args)?;
+    }
+    Ok(())
+  }
+
+  async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
+    let num_pe = self.num_pe as usize;
+    let mut buf = vec![wbpf_uapi_pe_exception_state::default(); num_pe];
+    let buf_size = std::mem::size_of::<wbpf_uapi_pe_exception_state>() * num_pe;
+    let file = self.file.lock().await;
+    loop {
+      let mut guard = file.readable().await?;
+
+      match guard.try_io(|inner| {
+        inner
+          .get_ref()
+          .read(unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf_size) })
+      }) {
+        Ok(result) => {
+          result?;
+          break;
+        }
+        Err(_would_block) => continue,
+      }
+    }
+
+    Ok(
+      buf
+        .iter()
+        .map(|x| ExceptionState::from_raw(x.pc, x.code, x.data))
+        .collect(),
+    )
+  }
+
+  fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters> {
+    let mut rsp: wbpf_uapi_performance_counters = Default::default();
+    let req: wbpf_uapi_read_performance_counters_args = wbpf_uapi_read_performance_counters_args {
+      pe_index,
+      out: &mut rsp,
+      size: std::mem::size_of::<wbpf_uapi_performance_counters>(),
+    };
+    unsafe {
+      crate::uapi::ioc_get_performance_counters(self.file_fd, &req)?;
+    }
+    Ok(PerfCounters {
+      cycles: rsp.cycles,
+      commits: rsp.commits,
+    })
+  }
+
+  fn dma_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+    let output_len = output.len();
+    let output_ptr = output.as_mut_ptr();
+    let args = wbpf_uapi_read_dm_args {
+      offset,
+      data: output_ptr,
+      data_len: output_len as u32,
+    };
+    unsafe {
+      crate::uapi::ioc_read_dm(self.file_fd, &args)?;
+    }
+    Ok(())
+  }
+
+  fn dma_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+    let data_len = data.len();
+    let data_ptr = data.as_ptr();
+    let args = wbpf_uapi_write_dm_args {
+      offset,
+      data: data_ptr,
+      data_len: data_len as u32,
+    };
+    unsafe {
+      crate::uapi::ioc_write_dm(self.file_fd, &args)?;
+    }
+    Ok(())
+  }
+
+  fn mmio_read(&self, base_offset: u32, output: &mut [u8]) -> Result<()> {
+    let mut index: usize = 0;
+    let len = output.len();
+    let base_offset = base_offset as usize;
+    self.unaligned_op(base_offset, len, |off, word| {
+      if word {
+        let word = unsafe {
+          std::ptr::read_volatile((self.mem.as_ptr() as *const u32).offset((off / 4) as _))
+        };
+        output[index..index + 4].copy_from_slice(&word.to_le_bytes());
+        index += 4;
+      } else {
+        let byte = unsafe { std::ptr::read_volatile(self.mem.as_ptr().offset(off as _)) };
+        output[index] = byte;
+        index += 1;
+      }
+      Ok(())
+    })?;
+    assert_eq!(index, len);
+    Ok(())
+  }
+
+  fn mmio_write(&self, base_offset: u32, data: &[u8]) -> Result<()> {
+    let base_offset = base_offset as usize;
+    self.unaligned_op(base_offset, data.len(), |off, word| {
+      if word {
+        let word = u32::from_le_bytes([
+          data[off - base_offset],
+          data[off - base_offset + 1],
+          data[off - base_offset + 2],
+          data[off - base_offset + 3],
+        ]);
+        unsafe {
+          std::ptr::write_volatile((self.mem.as_ptr() as *mut u32).offset((off / 4) as _), word);
+        }
+      } else {
+        unsafe {
+          std::ptr::write_volatile(
+            self.mem.as_mut_ptr().offset(off as _),
+            data[off - base_offset],
+          );
+        }
+      }
+      Ok(())
+    })?;
+    Ok(())
+  }
+}
@@ -0,0 +1,56 @@
+//! Transports used by `Device` to talk to processing elements.
+
+mod ioctl;
+mod recording;
+
+use std::future::Future;
+
+use anyhow::Result;
+
+use crate::{exception::ExceptionState, perf::PerfCounters};
+
+pub use self::ioctl::IoctlBackend;
+pub use self::recording::{BackendOp, RecordingBackend};
+
+/// Low-level operations provided by a wBPF device.
+///
+/// `dma_*` methods go through the DMA engine; `mmio_*` methods access the data memory directly.
+/// Backends without a separate MMIO path may implement both in terms of the same primitive.
+pub trait Backend: Send + Sync + 'static {
+  fn num_pe(&self) -> Result<u32>;
+
+  fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()>;
+
+  fn start(&self, pe_index: u32, pc: u32) -> Result<()>;
+
+  fn stop(&self, pe_index: u32) -> Result<()>;
+
+  /// Reads the exception state of all PEs.
+  fn read_exception_state(&self) -> impl Future<Output = Result<Vec<ExceptionState>>> + Send;
+
+  fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters>;
+
+  /// Whether `read_registers` and `write_register` are available. The kernel driver does not
+  /// expose PE registers, so only the emulator has register access.
+  fn has_register_access(&self) -> bool {
+    false
+  }
+
+  /// Reads r0-r10 of a PE that is not running.
+  fn read_registers(&self, _pe_index: u32) -> Result<[u64; 11]> {
+    anyhow::bail!("backend has no register access")
+  }
+
+  /// Writes one register of a PE that is not running.
+  fn write_register(&self, _pe_index: u32, _reg: u32, _value: u64) -> Result<()> {
+    anyhow::bail!("backend has no register access")
+  }
+
+  fn dma_read(&self, offset: u32, output: &mut [u8]) -> Result<()>;
+
+  fn dma_write(&self, offset: u32, data: &[u8]) -> Result<()>;
+
+  fn mmio_read(&self, offset: u32, output: &mut [u8]) -> Result<()>;
+
+  fn mmio_write(&self, offset: u32, data: &[u8]) -> Result<()>;
+}
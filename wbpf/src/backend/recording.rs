@@ -0,0 +1,243 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use crate::{exception::ExceptionState, perf::PerfCounters};
+
+use super::Backend;
+
+/// An operation observed by `RecordingBackend`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendOp {
+  LoadCode {
+    pe_index: u32,
+    offset: u32,
+    code: Vec<u8>,
+  },
+  Start {
+    pe_index: u32,
+    pc: u32,
+  },
+  Stop {
+    pe_index: u32,
+  },
+  ReadExceptionState,
+  ReadPerfCounters {
+    pe_index: u32,
+  },
+  ReadRegisters {
+    pe_index: u32,
+  },
+  WriteRegister {
+    pe_index: u32,
+    reg: u32,
+    value: u64,
+  },
+  DmaRead {
+    offset: u32,
+    len: usize,
+  },
+  DmaWrite {
+    offset: u32,
+    data: Vec<u8>,
+  },
+  MmioRead {
+    offset: u32,
+    len: usize,
+  },
+  MmioWrite {
+    offset: u32,
+    data: Vec<u8>,
+  },
+}
+
+/// A backend that forwards every operation to `inner` and keeps a log of them.
+pub struct RecordingBackend<B: Backend> {
+  inner: B,
+  ops: Mutex<Vec<BackendOp>>,
+}
+
+impl<B: Backend> RecordingBackend<B> {
+  pub fn new(inner: B) -> Self {
+    Self {
+      inner,
+      ops: Mutex::new(vec![]),
+    }
+  }
+
+  pub fn inner(&self) -> &B {
+    &self.inner
+  }
+
+  /// Returns the operations recorded so far.
+  pub fn ops(&self) -> Vec<BackendOp> {
+    self.ops.lock().unwrap().clone()
+  }
+
+  /// Returns and clears the operations recorded so far.
+  pub fn take_ops(&self) -> Vec<BackendOp> {
+    std::mem::take(&mut *self.ops.lock().unwrap())
+  }
+
+  fn record(&self, op: BackendOp) {
+    self.ops.lock().unwrap().push(op);
+  }
+}
+
+impl<B: Backend> Backend for RecordingBackend<B> {
+  fn num_pe(&self) -> Result<u32> {
+    self.inner.num_pe()
+  }
+
+  fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()> {
+    self.record(BackendOp::LoadCode {
+      pe_index,
+      offset,
+      code: code.to_vec(),
+    });
+    self.inner.load_code(pe_index, offset, code)
+  }
+
+  fn start(&self, pe_index: u32, pc: u32) -> Result<()> {
+    self.record(BackendOp::Start { pe_index, pc });
+    self.inner.start(pe_index, pc)
+  }
+
+  fn stop(&self, pe_index: u32) -> Result<()> {
+    self.record(BackendOp::Stop { pe_index });
+    self.inner.stop(pe_index)
+  }
+
+  async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
+    self.record(BackendOp::ReadExceptionState);
+    self.inner.read_exception_state().await
+  }
+
+  fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters> {
+    self.record(BackendOp::ReadPerfCounters { pe_index });
+    self.inner.read_perf_counters(pe_index)
+  }
+
+  fn has_register_access(&self) -> bool {
+    self.inner.has_register_access()
+  }
+
+  fn read_registers(&self, pe_index: u32) -> Result<[u64; 11]> {
+    self.record(BackendOp::ReadRegisters { pe_index });
+    self.inner.read_registers(pe_index)
+  }
+
+  fn write_register(&self, pe_index: u32, reg: u32, value: u64) -> Result<()> {
+    self.record(BackendOp::WriteRegister {
+      pe_index,
+      reg,
+      value,
+    });
+    self.inner.write_register(pe_index, reg, value)
+  }
+
+  fn dma_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+    self.record(BackendOp::DmaRead {
+      offset,
+      len: output.len(),
+    });
+    self.inner.dma_read(offset, output)
+  }
+
+  fn dma_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+    self.record(BackendOp::DmaWrite {
+      offset,
+      data: data.to_vec(),
+    });
+    self.inner.dma_write(offset, data)
+  }
+
+  fn mmio_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+    self.record(BackendOp::MmioRead {
+      offset,
+      len: output.len(),
+    });
+    self.inner.mmio_read(offset, output)
+  }
+
+  fn mmio_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+    self.record(BackendOp::MmioWrite {
+      offset,
+      data: data.to_vec(),
+    });
+    self.inner.mmio_write(offset, data)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::{
+    device::{Device, RunOptions},
+    emulator::{
+      tests::{link_test_image, state},
+      Emulator, EmulatorConfig,
+    },
+  };
+
+  #[tokio::test]
+  async fn records_run() {
+    let backend = Arc::new(RecordingBackend::new(Emulator::new(
+      EmulatorConfig::default(),
+    )));
+    let device = Device::new(backend.clone()).unwrap();
+    let image = link_test_image(Some(&["core_index"]));
+    let state = state(
+      r#"
+registers: [0, 0x2000, 0, 0, 0, 0, 0, 0, 0, 0, 0x3000]
+entryPoint: core_index
+dump: [{ offset: 0x2000, size: 8 }]
+"#,
+    );
+    device
+      .run(&image, &state, 0, &RunOptions::default())
+      .await
+      .unwrap();
+    let ops = backend
+      .take_ops()
+      .into_iter()
+      .filter(|x| {
+        !matches!(
+          x,
+          BackendOp::ReadExceptionState | BackendOp::ReadPerfCounters { .. }
+        )
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(ops[0], BackendOp::Stop { pe_index: 0 });
+    assert_eq!(
+      ops[1],
+      BackendOp::LoadCode {
+        pe_index: 0,
+        offset: 0,
+        code: image.code.clone(),
+      }
+    );
+    let entry_state = match &ops[2] {
+      BackendOp::DmaWrite { offset: 0, data } => data,
+      op => panic!("unexpected {:?}", op),
+    };
+    assert_eq!(entry_state.len(), 88);
+    assert_eq!(entry_state[8..16], 0x2000u64.to_le_bytes());
+    assert_eq!(entry_state[84..88], 0x3000u32.to_le_bytes());
+    assert_eq!(ops[3], BackendOp::Start { pe_index: 0, pc: 0 });
+    assert_eq!(
+      ops[4..],
+      [
+        BackendOp::ReadRegisters { pe_index: 0 },
+        BackendOp::ReadRegisters { pe_index: 0 },
+        BackendOp::DmaRead {
+          offset: 0x2000,
+          len: 8,
+        },
+      ]
+    );
+    assert!(backend.ops().is_empty());
+  }
+}
@@ -1,181 +1,286 @@
 use std::{
-  fs::{File, OpenOptions},
-  io::Read,
-  os::unix::prelude::AsRawFd,
+  fmt::Display,
   path::Path,
   sync::Arc,
+  time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use nix::fcntl;
+use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
-use tokio::{io::unix::AsyncFd, sync::Mutex};
 
 use crate::{
+  backend::{Backend, IoctlBackend},
   dm::DataMemory,
-  linker::image::Image,
+  dm_alloc::DmAllocator,
+  exception::{ExceptionCode, ExceptionState},
+  helper::{HelperAction, HelperContext, HelperRegistry, HOST_COMPLETE},
+  linker::{image::Image, symbolizer::Symbolizer},
   perf::PerfCounters,
-  uapi::{
-    ioc_get_num_pe, ioc_load_code, ioc_start, ioc_stop, wbpf_uapi_load_code_args, wbpf_uapi_num_pe,
-    wbpf_uapi_pe_exception_state, wbpf_uapi_performance_counters,
-    wbpf_uapi_read_performance_counters_args, wbpf_uapi_start_args, wbpf_uapi_stop_args,
-  },
 };
 
-#[derive(Clone)]
-pub struct Device {
-  pub(crate) file: Arc<Mutex<AsyncFd<File>>>,
-  pub(crate) file_fd: i32,
+pub struct Device<B: Backend = IoctlBackend> {
+  pub(crate) backend: Arc<B>,
   num_pe: u32,
 }
 
-#[derive(Clone, Debug)]
-pub struct ExceptionState {
+impl<B: Backend> Clone for Device<B> {
+  fn clone(&self) -> Self {
+    Self {
+      backend: self.backend.clone(),
+      num_pe: self.num_pe,
+    }
+  }
+}
+
+pub use crate::state::MachineState;
+
+/// How long the PE gets to acknowledge a stop request after a run times out.
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Options controlling how `Device::run` executes and what it collects.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RunOptions {
+  /// Data memory regions to read back once the run ends.
+  pub dump_regions: Vec<DmRegion>,
+  /// Wall-clock limit for the whole run, including loading the image.
+  pub timeout: Option<Duration>,
+  /// Cycle limit for the program. Checked between exception state polls, so a run may overshoot
+  /// it slightly.
+  pub cycle_budget: Option<u64>,
+}
+
+impl RunOptions {
+  /// Returns a copy of these options that additionally dumps `regions`.
+  pub fn with_dump_regions(&self, regions: &[DmRegion]) -> RunOptions {
+    let mut options = self.clone();
+    options.dump_regions.extend_from_slice(regions);
+    options
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutReason {
+  Deadline,
+  CycleBudget,
+}
+
+/// Returned when a PE does not stop within the allotted time or cycle budget. The PE has been
+/// asked to stop by the time this is returned.
+#[derive(Debug, thiserror::Error)]
+#[error(
+  "PE {pe_index} timed out ({reason}) at pc {pc:#x}: cycles={} commits={}",
+  perf.cycles,
+  perf.commits
+)]
+pub struct TimeoutError {
+  pub pe_index: u32,
+  pub reason: TimeoutReason,
+  /// Last pc observed before giving up.
   pub pc: u32,
-  pub code: u32,
-  pub data: u64,
+  /// For `run`, counter deltas since the PE was started; otherwise the raw counter values.
+  pub perf: PerfCounters,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Display for TimeoutReason {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      TimeoutReason::Deadline => write!(f, "deadline exceeded"),
+      TimeoutReason::CycleBudget => write!(f, "cycle budget exhausted"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DmRegion {
+  pub offset: u32,
+  pub size: u32,
+  /// Label carried over to the snapshot of this region.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct MachineState {
-  pub registers: Vec<i64>,
-  pub entry_point: String,
+pub struct DmSnapshot {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  pub offset: u32,
+  #[serde(with = "hex::serde")]
+  pub data: Vec<u8>,
 }
 
-impl Device {
+/// Outcome of `Device::run`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RunResult {
+  pub pe_index: u32,
+  /// Exception state the PE stopped with.
+  pub exception: ExceptionState,
+  /// Symbolized location of the exception pc, if it is inside a function.
+  pub location: Option<String>,
+  /// Value of r0 when the PE stopped. Unknown if the backend cannot read registers.
+  pub return_value: Option<u64>,
+  /// Perf counter deltas over the run.
+  pub perf: PerfCounters,
+  /// Wall-clock time from starting the PE until it stopped.
+  pub elapsed: Duration,
+  pub dm_snapshots: Vec<DmSnapshot>,
+}
+
+/// Perf counters and time at which a PE was launched.
+pub(crate) struct Launch {
+  pub start_perfctr: PerfCounters,
+  pub start_time: Instant,
+}
+
+impl Device<IoctlBackend> {
   pub async fn open(path: &Path) -> Result<Self> {
-    let file = OpenOptions::new().read(true).write(true).open(path)?;
-    let file_fd = file.as_raw_fd();
-    fcntl::fcntl(
-      file_fd,
-      fcntl::F_SETFL(fcntl::OFlag::O_CLOEXEC | fcntl::OFlag::O_NONBLOCK),
-    )?;
-    let mut dev = Device {
-      file: Arc::new(Mutex::new(AsyncFd::new(file)?)),
-      file_fd,
-      num_pe: 0,
-    };
-    dev.update_num_pe()?;
+    let dev = Device::new(Arc::new(IoctlBackend::open(path)?))?;
 
     let es = dev.read_exception_state().await?;
     log::info!("initial exception state: {:?}", es);
     Ok(dev)
   }
+}
 
-  pub async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
-    let mut buf = vec![wbpf_uapi_pe_exception_state::default(); self.num_pe as usize];
-    let buf_size = std::mem::size_of::<wbpf_uapi_pe_exception_state>() * self.num_pe as usize;
-    let file = self.file.lock().await;
-    loop {
-      let mut guard = file.readable().await?;
-
-      match guard.try_io(|inner| {
-        inner
-          .get_ref()
-          .read(unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf_size) })
-      }) {
-        Ok(result) => {
-          result?;
-          break;
-        }
-        Err(_would_block) => continue,
-      }
-    }
+impl<B: Backend> Device<B> {
+  pub fn new(backend: Arc<B>) -> Result<Self> {
+    let num_pe = backend.num_pe()?;
+    Ok(Device { backend, num_pe })
+  }
 
-    Ok(
-      buf
-        .iter()
-        .map(|x| ExceptionState {
-          pc: x.pc,
-          code: x.code,
-          data: x.data,
-        })
-        .collect(),
-    )
+  pub fn backend(&self) -> &Arc<B> {
+    &self.backend
   }
 
-  fn update_num_pe(&mut self) -> Result<()> {
-    let mut rsp: wbpf_uapi_num_pe = Default::default();
-    unsafe {
-      ioc_get_num_pe(self.file_fd, &mut rsp)?;
-    }
-    self.num_pe = rsp.num_pe;
-    Ok(())
+  pub async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
+    self.backend.read_exception_state().await
   }
 
   pub fn num_pe(&self) -> u32 {
     self.num_pe
   }
 
-  pub async fn data_memory(&self) -> Result<DataMemory> {
-    DataMemory::new(self.clone()).await
+  pub async fn data_memory(&self) -> Result<DataMemory<B>> {
+    Ok(DataMemory::new(self.clone()))
   }
 
   pub fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()> {
-    let args = wbpf_uapi_load_code_args {
-      pe_index,
-      offset,
-      code: code.as_ptr(),
-      code_len: code.len() as u32,
-    };
-    unsafe {
-      ioc_load_code(self.file_fd, &args)?;
-    }
-    Ok(())
+    self.backend.load_code(pe_index, offset, code)
   }
 
   pub fn stop(&self, pe_index: u32) -> Result<()> {
-    let args = wbpf_uapi_stop_args { pe_index };
-    unsafe {
-      ioc_stop(self.file_fd, &args)?;
-    }
-    Ok(())
+    self.backend.stop(pe_index)
   }
 
   pub async fn stop_and_wait(&self, pe_index: u32) -> Result<()> {
     self.stop(pe_index)?;
+    self.wait_for_stop(pe_index, None).await
+  }
+
+  /// Like `stop_and_wait`, but fails with a `TimeoutError` if the PE does not acknowledge the stop
+  /// request within `timeout`.
+  pub async fn stop_and_wait_timeout(&self, pe_index: u32, timeout: Duration) -> Result<()> {
+    self.stop(pe_index)?;
+    self
+      .wait_for_stop(pe_index, Some(Instant::now() + timeout))
+      .await
+  }
 
+  pub(crate) async fn wait_for_stop(&self, pe_index: u32, deadline: Option<Instant>) -> Result<()> {
+    let mut last_pc = 0;
     loop {
-      let es = self.read_exception_state().await?;
-      let es = &es[pe_index as usize];
+      match self.poll_pe(pe_index, deadline).await? {
+        Some(es) if es.code == ExceptionCode::Interrupted => return Ok(()),
+        Some(es) => last_pc = es.pc,
+        None => {
+          return Err(
+            TimeoutError {
+              pe_index,
+              reason: TimeoutReason::Deadline,
+              pc: last_pc,
+              perf: self.read_perf_counters(pe_index)?,
+            }
+            .into(),
+          )
+        }
+      }
+    }
+  }
 
-      // STOP | INTR
-      if es.code == 0x80000007u32 {
+  /// Reads the exception state of one PE. Returns `None` once `deadline` has passed.
+  pub(crate) async fn poll_pe(
+    &self,
+    pe_index: u32,
+    deadline: Option<Instant>,
+  ) -> Result<Option<ExceptionState>> {
+    let es = match deadline {
+      Some(deadline) => {
+        if Instant::now() >= deadline {
+          return Ok(None);
+        }
+        match tokio::time::timeout_at(deadline.into(), self.read_exception_state()).await {
+          Ok(es) => es?,
+          Err(_) => return Ok(None),
+        }
+      }
+      None => self.read_exception_state().await?,
+    };
+    let es = es
+      .into_iter()
+      .nth(pe_index as usize)
+      .ok_or_else(|| anyhow::anyhow!("invalid pe index {}", pe_index))?;
+    Ok(Some(es))
+  }
+
+  /// Stops a PE whose run timed out and builds the error describing where it was.
+  async fn abort_run(
+    &self,
+    pe_index: u32,
+    reason: TimeoutReason,
+    mut pc: u32,
+    start_perfctr: &PerfCounters,
+  ) -> Result<TimeoutError> {
+    self.stop(pe_index)?;
+    let grace_deadline = Instant::now() + STOP_GRACE_PERIOD;
+    while let Some(es) = self.poll_pe(pe_index, Some(grace_deadline)).await? {
+      pc = es.pc;
+      if !es.code.is_running() {
         break;
       }
     }
-    Ok(())
+    let end_perfctr = self.read_perf_counters(pe_index)?;
+    Ok(TimeoutError {
+      pe_index,
+      reason,
+      pc,
+      perf: PerfCounters {
+        cycles: end_perfctr.cycles - start_perfctr.cycles,
+        commits: end_perfctr.commits - start_perfctr.commits,
+      },
+    })
   }
 
   pub fn start(&self, pe_index: u32, pc: u32) -> Result<()> {
-    let args = wbpf_uapi_start_args { pe_index, pc };
-    unsafe {
-      ioc_start(self.file_fd, &args)?;
-    }
-    Ok(())
+    self.backend.start(pe_index, pc)
   }
 
   pub fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters> {
-    let mut rsp: wbpf_uapi_performance_counters = Default::default();
-    let req: wbpf_uapi_read_performance_counters_args = wbpf_uapi_read_performance_counters_args {
-      pe_index,
-      out: &mut rsp,
-      size: std::mem::size_of::<wbpf_uapi_performance_counters>(),
-    };
-    unsafe {
-      crate::uapi::ioc_get_performance_counters(self.file_fd, &req)?;
-    }
-    Ok(PerfCounters {
-      cycles: rsp.cycles,
-      commits: rsp.commits,
-    })
+    self.backend.read_perf_counters(pe_index)
   }
 
   pub async fn load_image(&self, pe_index: u32, image: &Image) -> Result<()> {
     self.load_code(pe_index, 0, &image.code)?;
+    self.load_data(image).await
+  }
 
-    if image.data.len() != 0 {
+  /// Writes the data image of `image` to data memory, which is shared by all PEs, and clears the
+  /// zero-initialized region after it.
+  pub async fn load_data(&self, image: &Image) -> Result<()> {
+    if image.data.len() != 0 || image.bss_size != 0 {
       let platform = image
         .platform
         .as_ref()
@@ -185,40 +290,89 @@ impl Device {
       }
       let dm = self.data_memory().await?;
       let mut data = image.data.clone();
-      // Align data to 8 bytes
-      let len_diff = 7 - (data.len() + 7) % 8;
-      data.extend((0..len_diff).map(|_| 0u8));
+      // Zero-fill the bss region and align to 8 bytes
+      let len = image.data.len() + image.bss_size as usize;
+      data.resize(len.div_ceil(8) * 8, 0);
       dm.do_dma_write(platform.data_offset as u32, &data)?;
       log::debug!(
-        "written data memory with offset {} and length {}",
+        "written data memory with offset {}, length {} and zero-fill length {}",
         platform.data_offset,
-        image.data.len()
+        image.data.len(),
+        image.bss_size
       );
     }
     Ok(())
   }
 
-  pub async fn run(&self, image: &Image, state: &MachineState, pe_index: u32) -> Result<()> {
-    if state.registers.len() != 11 {
-      return Err(anyhow::anyhow!("invalid state"));
-    }
+  pub async fn run(
+    &self,
+    image: &Image,
+    state: &MachineState,
+    pe_index: u32,
+    options: &RunOptions,
+  ) -> Result<RunResult> {
+    self
+      .run_with_helpers(image, state, pe_index, options, &mut HelperRegistry::new())
+      .await
+  }
 
-    self.stop_and_wait(pe_index).await?;
+  /// Runs `image` on a PE, servicing host helper calls with `helpers` until the program calls a
+  /// helper that completes the run or stops for another reason.
+  ///
+  /// Servicing helpers needs register access, which only the emulator backend has. On other
+  /// backends the run ends at the first helper call, with the PE stopped at the call.
+  pub async fn run_with_helpers(
+    &self,
+    image: &Image,
+    state: &MachineState,
+    pe_index: u32,
+    options: &RunOptions,
+    helpers: &mut HelperRegistry<B>,
+  ) -> Result<RunResult> {
+    let deadline = options.timeout.map(|x| Instant::now() + x);
+    self.stop(pe_index)?;
+    self.wait_for_stop(pe_index, deadline).await?;
     self.load_image(pe_index, &image).await?;
+    let dm = self.data_memory().await?;
+    let resolved = state.resolve(&dm, image, &mut DmAllocator::for_image(image)?)?;
+    resolved.check_stack(image, &state.entry_point)?;
+    let launch = self
+      .launch(image, &state.entry_point, &resolved.registers, pe_index)
+      .await?;
+    let options = options.with_dump_regions(&resolved.dump_regions);
+    self
+      .supervise(image, pe_index, &options, helpers, deadline, launch)
+      .await
+  }
 
+  /// Writes the entry state to the start of data memory and starts the PE at the entry
+  /// trampoline. The trampoline reads the state back, so no other PE may be launched until it has
+  /// run.
+  pub(crate) async fn launch(
+    &self,
+    image: &Image,
+    entry_point: &str,
+    registers: &[u64; 11],
+    pe_index: u32,
+  ) -> Result<Launch> {
     let offset_table = image
       .offset_table
       .as_ref()
       .ok_or_else(|| anyhow::anyhow!("no offset table"))?;
 
-    let offset = *offset_table
-      .func_offsets
-      .get(&state.entry_point)
-      .ok_or_else(|| anyhow::anyhow!("no entry point"))?;
-    let mut state_snapshot = [0u64; 11];
-    for i in 0..11 {
-      state_snapshot[i] = state.registers[i] as u64;
-    }
+    let offset = if offset_table.entry_points.is_empty() {
+      *offset_table
+        .func_offsets
+        .get(entry_point)
+        .ok_or_else(|| anyhow::anyhow!("no entry point"))?
+    } else {
+      offset_table
+        .entry_points
+        .get(entry_point)
+        .ok_or_else(|| anyhow::anyhow!("{} is not an exported entry point", entry_point))?
+        .offset
+    };
+    let mut state_snapshot = *registers;
     state_snapshot[10] = (state_snapshot[10] << 32) | (offset as u64);
     let size = std::mem::size_of_val(&state_snapshot);
     let dm = self.data_memory().await?;
@@ -226,22 +380,243 @@ impl Device {
       std::slice::from_raw_parts(state_snapshot.as_ptr() as *const u8, size)
     })?;
     let start_perfctr = self.read_perf_counters(pe_index)?;
+    let start_time = Instant::now();
     self.start(pe_index, 0)?;
+    Ok(Launch {
+      start_perfctr,
+      start_time,
+    })
+  }
+
+  /// Waits for a launched PE to stop, servicing helper calls along the way.
+  pub(crate) async fn supervise(
+    &self,
+    image: &Image,
+    pe_index: u32,
+    options: &RunOptions,
+    helpers: &mut HelperRegistry<B>,
+    deadline: Option<Instant>,
+    launch: Launch,
+  ) -> Result<RunResult> {
+    let Launch {
+      start_perfctr,
+      start_time,
+    } = launch;
+    let helper_names = image
+      .platform
+      .iter()
+      .flat_map(|x| x.helpers.iter())
+      .map(|(name, index)| (*index as u32, name.as_str()))
+      .collect::<FnvHashMap<_, _>>();
+    let dm = self.data_memory().await?;
+
+    let mut last_pc = 0;
     let es = loop {
-      let es = self.read_exception_state().await?;
-      let es = es.into_iter().nth(pe_index as usize).unwrap();
-      if es.code & 0x80000000u32 != 0 {
+      let es = match self.poll_pe(pe_index, deadline).await? {
+        Some(es) => es,
+        None => {
+          let reason = TimeoutReason::Deadline;
+          return Err(
+            self
+              .abort_run(pe_index, reason, last_pc, &start_perfctr)
+              .await?
+              .into(),
+          );
+        }
+      };
+      last_pc = es.pc;
+      let index = match es.code {
+        ExceptionCode::Running => {
+          if let Some(budget) = options.cycle_budget {
+            let perfctr = self.read_perf_counters(pe_index)?;
+            if perfctr.cycles - start_perfctr.cycles >= budget {
+              let reason = TimeoutReason::CycleBudget;
+              return Err(
+                self
+                  .abort_run(pe_index, reason, es.pc, &start_perfctr)
+                  .await?
+                  .into(),
+              );
+            }
+          }
+          continue;
+        }
+        ExceptionCode::HelperCall { index } => index,
+        _ => break es,
+      };
+      let name = *helper_names
+        .get(&index)
+        .ok_or_else(|| anyhow::anyhow!("call to helper {} not in host platform", index))?;
+      if !self.backend.has_register_access() {
+        if name != HOST_COMPLETE {
+          log::warn!(
+            "not servicing helper {} on PE {}: backend has no register access",
+            name,
+            pe_index
+          );
+        }
         break es;
       }
+      let f = helpers
+        .get_mut(name)
+        .ok_or_else(|| anyhow::anyhow!("no host implementation for helper {}", name))?;
+      let regs = self.backend.read_registers(pe_index)?;
+      let mut ctx = HelperContext {
+        pe_index,
+        args: [regs[1], regs[2], regs[3], regs[4], regs[5]],
+        dm: &dm,
+      };
+      match f(&mut ctx)? {
+        HelperAction::Return(value) => {
+          self.backend.write_register(pe_index, 0, value)?;
+          self.start(pe_index, es.pc + 8)?;
+        }
+        HelperAction::Complete => break es,
+      }
     };
+    let elapsed = start_time.elapsed();
     let end_perfctr = self.read_perf_counters(pe_index)?;
-    println!("new es: {:?}", es);
-    println!(
-      "cycles={} commits={}",
-      end_perfctr.cycles - start_perfctr.cycles,
-      end_perfctr.commits - start_perfctr.commits
+    let return_value = if self.backend.has_register_access() {
+      Some(self.backend.read_registers(pe_index)?[0])
+    } else {
+      None
+    };
+
+    let mut dm_snapshots = Vec::with_capacity(options.dump_regions.len());
+    for region in &options.dump_regions {
+      let mut data = vec![0u8; region.size as usize];
+      dm.do_dma_read(region.offset, &mut data)?;
+      dm_snapshots.push(DmSnapshot {
+        name: region.name.clone(),
+        offset: region.offset,
+        data,
+      });
+    }
+
+    Ok(RunResult {
+      pe_index,
+      location: Symbolizer::new(image)
+        .symbolize(es.pc)
+        .map(|x| x.to_string()),
+      exception: es,
+      return_value,
+      perf: PerfCounters {
+        cycles: end_perfctr.cycles - start_perfctr.cycles,
+        commits: end_perfctr.commits - start_perfctr.commits,
+      },
+      elapsed,
+      dm_snapshots,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::{
+    tests::{link_test_image, state},
+    Emulator, EmulatorConfig,
+  };
+
+  const CALL_HOST_STATE: &str = r#"
+buffers:
+  out: { fill: "00", size: 8 }
+registers: [0, 40, 2, { buffer: out }, 0, 0, 0, 0, 0, 0, { stack: 0x100 }]
+entryPoint: call_host
+dump: [{ buffer: out }]
+"#;
+
+  /// Forwards to the emulator but, like the kernel driver, has no register access.
+  struct NoRegisterAccess(Emulator);
+
+  impl Backend for NoRegisterAccess {
+    fn num_pe(&self) -> Result<u32> {
+      self.0.num_pe()
+    }
+
+    fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()> {
+      self.0.load_code(pe_index, offset, code)
+    }
+
+    fn start(&self, pe_index: u32, pc: u32) -> Result<()> {
+      self.0.start(pe_index, pc)
+    }
+
+    fn stop(&self, pe_index: u32) -> Result<()> {
+      self.0.stop(pe_index)
+    }
+
+    async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
+      self.0.read_exception_state().await
+    }
+
+    fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters> {
+      self.0.read_perf_counters(pe_index)
+    }
+
+    fn dma_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+      self.0.dma_read(offset, output)
+    }
+
+    fn dma_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+      self.0.dma_write(offset, data)
+    }
+
+    fn mmio_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+      self.0.mmio_read(offset, output)
+    }
+
+    fn mmio_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+      self.0.mmio_write(offset, data)
+    }
+  }
+
+  #[tokio::test]
+  async fn services_host_helpers() {
+    let device = Device::new(Arc::new(Emulator::new(EmulatorConfig::default()))).unwrap();
+    let mut helpers = HelperRegistry::new();
+    helpers.register("host_add", |ctx| {
+      Ok(HelperAction::Return(ctx.args[0] + ctx.args[1]))
+    });
+    let result = device
+      .run_with_helpers(
+        &link_test_image(None),
+        &state(CALL_HOST_STATE),
+        0,
+        &RunOptions::default(),
+        &mut helpers,
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      result.exception.code,
+      ExceptionCode::HelperCall { index: 1024 }
     );
+    assert_eq!(result.return_value, Some(42));
+    assert_eq!(result.dm_snapshots[0].data, 42u64.to_le_bytes());
+  }
 
-    Ok(())
+  #[tokio::test]
+  async fn ends_run_at_helper_call_without_register_access() {
+    let backend = NoRegisterAccess(Emulator::new(EmulatorConfig::default()));
+    let device = Device::new(Arc::new(backend)).unwrap();
+    let mut helpers = HelperRegistry::new();
+    helpers.register("host_add", |_| panic!("serviced without register access"));
+    let result = device
+      .run_with_helpers(
+        &link_test_image(None),
+        &state(CALL_HOST_STATE),
+        0,
+        &RunOptions::default(),
+        &mut helpers,
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      result.exception.code,
+      ExceptionCode::HelperCall { index: 1025 }
+    );
+    assert_eq!(result.return_value, None);
+    assert_eq!(result.dm_snapshots[0].data, [0; 8]);
   }
 }
@@ -0,0 +1,342 @@
+//! Allocation of data memory regions for passing arguments to programs.
+//!
+//! Data memory is laid out as follows:
+//!
+//! - `0..0x100`: entry state written by `Device::run` and consumed by the entry trampoline.
+//! - `data_offset..data_offset + data.len() + bss_size`: the image's data, followed by its
+//!   zero-initialized data.
+//! - The rest is free for argument buffers and stacks handed out by `DmAllocator`.
+
+use std::{marker::PhantomData, ops::Range};
+
+use anyhow::Result;
+
+use crate::{
+  backend::Backend,
+  device::DmRegion,
+  dm::{DataMemory, DATA_MEMORY_SIZE},
+  linker::image::Image,
+};
+
+/// Data memory below this offset is reserved for the entry state.
+pub(crate) const RESERVED_SIZE: u32 = 0x100;
+
+/// Types that can be copied to and from data memory byte by byte.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` or primitive, contain no padding, pointers or references,
+/// and be valid for any bit pattern. The device is little-endian, so multi-byte fields are only
+/// meaningful on little-endian hosts.
+pub unsafe trait Plain: Copy + 'static {}
+
+macro_rules! impl_plain {
+  ($($t:ty),*) => {
+    $(unsafe impl Plain for $t {})*
+  };
+}
+
+impl_plain!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// A bump allocator over the free part of data memory.
+#[derive(Debug, Clone)]
+pub struct DmAllocator {
+  range: Range<u32>,
+  next: u32,
+  /// Ranges allocations have to go around.
+  reserved: Vec<Range<u32>>,
+}
+
+/// A typed region of data memory holding `len` values of `T`.
+#[derive(Debug)]
+pub struct DmBuffer<T: Plain> {
+  addr: u32,
+  len: usize,
+  _marker: PhantomData<T>,
+}
+
+impl<T: Plain> Clone for DmBuffer<T> {
+  fn clone(&self) -> Self {
+    Self {
+      addr: self.addr,
+      len: self.len,
+      _marker: PhantomData,
+    }
+  }
+}
+
+impl DmAllocator {
+  /// Creates an allocator handing out memory from `range`.
+  pub fn new(range: Range<u32>) -> Self {
+    Self {
+      next: range.start,
+      range,
+      reserved: vec![],
+    }
+  }
+
+  /// Creates an allocator over the data memory not used by the entry state or the data of
+  /// `image`.
+  pub fn for_image(image: &Image) -> Result<Self> {
+    let mut start = RESERVED_SIZE;
+    if !image.data.is_empty() || image.bss_size != 0 {
+      let platform = image
+        .platform
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no platform"))?;
+      let end = platform.data_offset as u64 + image.data.len() as u64 + image.bss_size as u64;
+      start = start.max(u32::try_from(end)?);
+    }
+    if start as usize > DATA_MEMORY_SIZE {
+      anyhow::bail!("image data does not fit in data memory");
+    }
+    Ok(Self::new(start..DATA_MEMORY_SIZE as u32))
+  }
+
+  /// Releases all allocations.
+  pub fn reset(&mut self) {
+    self.next = self.range.start;
+  }
+
+  /// Number of bytes left, ignoring alignment and reserved ranges.
+  pub fn remaining(&self) -> u32 {
+    self.range.end - self.next
+  }
+
+  /// The memory handed out since the allocator was created or reset.
+  pub fn allocated(&self) -> Range<u32> {
+    self.range.start..self.next
+  }
+
+  /// Keeps later allocations out of `range`, e.g. because a buffer was placed there by hand.
+  pub fn reserve(&mut self, range: Range<u32>) {
+    if !range.is_empty() {
+      self.reserved.push(range);
+    }
+  }
+
+  /// Splits the remaining memory into `n` equally sized allocators, e.g. one per PE. Returns no
+  /// allocators if `n` is zero.
+  pub fn split(&self, n: u32) -> Vec<DmAllocator> {
+    if n == 0 {
+      return vec![];
+    }
+    let chunk = (self.remaining() / n) & !7;
+    (0..n)
+      .map(|i| {
+        let start = self.next + i * chunk;
+        DmAllocator {
+          reserved: self.reserved.clone(),
+          ..DmAllocator::new(start..start + chunk)
+        }
+      })
+      .collect()
+  }
+
+  /// Allocates `size` bytes aligned to `align`, which must be a power of two.
+  pub fn alloc(&mut self, size: u32, align: u32) -> Result<DmRegion> {
+    assert!(align.is_power_of_two());
+    let mut start = self.next;
+    let offset = loop {
+      let offset = start
+        .checked_add(align - 1)
+        .map(|x| x & !(align - 1))
+        .filter(|x| x.checked_add(size).is_some_and(|end| end <= self.range.end))
+        .ok_or_else(|| {
+          anyhow::anyhow!(
+            "out of data memory: requested {} bytes, {} remaining",
+            size,
+            self.remaining()
+          )
+        })?;
+      match self
+        .reserved
+        .iter()
+        .find(|x| x.start < offset + size && offset < x.end)
+      {
+        Some(reserved) => start = reserved.end,
+        None => break offset,
+      }
+    };
+    self.next = offset + size;
+    Ok(DmRegion {
+      offset,
+      size,
+      name: None,
+    })
+  }
+
+  /// Allocates an uninitialized array of `len` values.
+  pub fn alloc_array<T: Plain>(&mut self, len: usize) -> Result<DmBuffer<T>> {
+    let size = u32::try_from(std::mem::size_of::<T>() * len)?;
+    // Align to at least 8 bytes so that buffers can be accessed with any load width.
+    let align = std::mem::align_of::<T>().max(8) as u32;
+    let region = self.alloc(size, align)?;
+    Ok(DmBuffer {
+      addr: region.offset,
+      len,
+      _marker: PhantomData,
+    })
+  }
+
+  /// Allocates an array and initializes it with `data`.
+  pub fn alloc_slice<T: Plain, B: Backend>(
+    &mut self,
+    dm: &DataMemory<B>,
+    data: &[T],
+  ) -> Result<DmBuffer<T>> {
+    let buf = self.alloc_array(data.len())?;
+    buf.write(dm, data)?;
+    Ok(buf)
+  }
+
+  /// Allocates a single value and initializes it.
+  pub fn alloc_value<T: Plain, B: Backend>(
+    &mut self,
+    dm: &DataMemory<B>,
+    value: &T,
+  ) -> Result<DmBuffer<T>> {
+    self.alloc_slice(dm, std::slice::from_ref(value))
+  }
+
+  /// Allocates a stack of `size` bytes and returns the initial value of r10 for it.
+  pub fn alloc_stack(&mut self, size: u32) -> Result<u32> {
+    let region = self.alloc(size, 8)?;
+    Ok(region.offset + region.size)
+  }
+}
+
+impl<T: Plain> DmBuffer<T> {
+  /// Device address of the buffer, suitable for passing as a pointer argument.
+  pub fn addr(&self) -> u32 {
+    self.addr
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn region(&self) -> DmRegion {
+    DmRegion {
+      offset: self.addr,
+      size: (std::mem::size_of::<T>() * self.len) as u32,
+      name: None,
+    }
+  }
+
+  /// Writes `data` to the start of the buffer.
+  pub fn write<B: Backend>(&self, dm: &DataMemory<B>, data: &[T]) -> Result<()> {
+    if data.len() > self.len {
+      anyhow::bail!(
+        "writing {} values to a buffer of length {}",
+        data.len(),
+        self.len
+      );
+    }
+    let bytes = unsafe {
+      std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+    };
+    dm.do_dma_write(self.addr, bytes)
+  }
+
+  /// Reads the whole buffer.
+  pub fn read<B: Backend>(&self, dm: &DataMemory<B>) -> Result<Vec<T>> {
+    let size = std::mem::size_of::<T>();
+    let mut bytes = vec![0u8; size * self.len];
+    dm.do_dma_read(self.addr, &mut bytes)?;
+    Ok(
+      bytes
+        .chunks_exact(size)
+        .map(|x| unsafe { std::ptr::read_unaligned(x.as_ptr() as *const T) })
+        .collect(),
+    )
+  }
+
+  /// Reads the first value of the buffer.
+  pub fn read_value<B: Backend>(&self, dm: &DataMemory<B>) -> Result<T> {
+    self
+      .read(dm)?
+      .into_iter()
+      .next()
+      .ok_or_else(|| anyhow::anyhow!("empty buffer"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn range(alloc: &DmAllocator) -> Range<u32> {
+    alloc.next..alloc.range.end
+  }
+
+  #[test]
+  fn alloc_aligns_and_bumps() {
+    let mut alloc = DmAllocator::new(0x101..0x200);
+    let a = alloc.alloc(3, 1).unwrap();
+    assert_eq!((a.offset, a.size), (0x101, 3));
+    let b = alloc.alloc(16, 8).unwrap();
+    assert_eq!((b.offset, b.size), (0x108, 16));
+    let c = alloc.alloc(0, 64).unwrap();
+    assert_eq!((c.offset, c.size), (0x140, 0));
+    assert_eq!(alloc.remaining(), 0xc0);
+  }
+
+  #[test]
+  fn alloc_fails_when_out_of_memory() {
+    let mut alloc = DmAllocator::new(0x100..0x120);
+    assert!(alloc.alloc(0x21, 1).is_err());
+    // Padding for alignment counts against the remaining memory.
+    alloc.alloc(1, 1).unwrap();
+    assert!(alloc.alloc(0x20, 8).is_err());
+    assert_eq!(alloc.alloc(0x18, 8).unwrap().offset, 0x108);
+    assert_eq!(alloc.remaining(), 0);
+    assert!(alloc.alloc(u32::MAX, 1).is_err());
+  }
+
+  #[test]
+  fn alloc_stack_returns_top() {
+    let mut alloc = DmAllocator::new(0x104..0x1000);
+    assert_eq!(alloc.alloc_stack(0x100).unwrap(), 0x208);
+    assert_eq!(alloc.alloc_stack(0x10).unwrap(), 0x218);
+    alloc.reset();
+    assert_eq!(alloc.alloc_stack(0x10).unwrap(), 0x118);
+  }
+
+  #[test]
+  fn alloc_skips_reserved_ranges() {
+    let mut alloc = DmAllocator::new(0x100..0x200);
+    alloc.reserve(0x108..0x110);
+    alloc.reserve(0x110..0x121);
+    assert_eq!(alloc.alloc(8, 8).unwrap().offset, 0x100);
+    assert_eq!(alloc.alloc(8, 8).unwrap().offset, 0x128);
+    // The reservations carry over to split allocators.
+    let mut parts = DmAllocator::new(0x100..0x200).split(2);
+    parts[0].reserve(0x100..0x180);
+    assert!(parts[0].alloc(1, 1).is_err());
+    assert_eq!(parts[1].alloc(1, 1).unwrap().offset, 0x180);
+  }
+
+  #[test]
+  fn split_divides_remaining_memory() {
+    let mut alloc = DmAllocator::new(0x100..0x1000);
+    alloc.alloc(0x10, 8).unwrap();
+    let parts = alloc.split(3);
+    // 0xef0 / 3 = 0x4fa, rounded down to a multiple of 8.
+    assert_eq!(
+      parts.iter().map(range).collect::<Vec<_>>(),
+      [0x110..0x608, 0x608..0xb00, 0xb00..0xff8]
+    );
+  }
+
+  #[test]
+  fn split_into_zero_parts() {
+    assert!(DmAllocator::new(0x100..0x1000).split(0).is_empty());
+  }
+}
@@ -0,0 +1,318 @@
+//! In-process software model of a wBPF device.
+//!
+//! The emulator models an array of processing elements sharing one data memory. Each PE has its
+//! own code memory and register file, and implements the wBPF-specific control flow emitted by the
+//! global linker (`JA` with `src=1` returns, `JA` with `src=2` calls with stack adjustment). Machine
+//! helpers are serviced by the PE; any other helper call traps to the host.
+//!
+//! PEs only make progress while the exception state is being polled, which keeps execution
+//! deterministic and independent of host scheduling.
+
+mod pe;
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{backend::Backend, exception::ExceptionState, perf::PerfCounters};
+
+use self::pe::ProcessingElement;
+
+pub use crate::dm::DATA_MEMORY_SIZE;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmulatorConfig {
+  /// Number of processing elements.
+  pub num_pe: u32,
+  /// Size of the code memory of each PE, in bytes.
+  pub code_memory_size: usize,
+  /// Maximum number of instructions each running PE retires per exception state poll.
+  pub insns_per_poll: u64,
+}
+
+impl Default for EmulatorConfig {
+  fn default() -> Self {
+    Self {
+      num_pe: 1,
+      code_memory_size: 65536,
+      insns_per_poll: 65536,
+    }
+  }
+}
+
+pub struct Emulator {
+  config: EmulatorConfig,
+  state: Mutex<EmulatorState>,
+}
+
+struct EmulatorState {
+  dm: Vec<u8>,
+  pes: Vec<ProcessingElement>,
+}
+
+impl Emulator {
+  pub fn new(config: EmulatorConfig) -> Self {
+    let pes = (0..config.num_pe)
+      .map(|i| ProcessingElement::new(i, config.code_memory_size))
+      .collect();
+    Self {
+      state: Mutex::new(EmulatorState {
+        dm: vec![0u8; DATA_MEMORY_SIZE],
+        pes,
+      }),
+      config,
+    }
+  }
+
+  fn with_pe<R>(&self, pe_index: u32, f: impl FnOnce(&mut ProcessingElement) -> R) -> Result<R> {
+    let mut state = self.state.lock().unwrap();
+    let pe = state
+      .pes
+      .get_mut(pe_index as usize)
+      .ok_or_else(|| anyhow::anyhow!("invalid pe index {}", pe_index))?;
+    Ok(f(pe))
+  }
+
+  /// Runs every running PE for up to `insns_per_poll` instructions and returns the resulting
+  /// exception states.
+  fn poll_exception_state(&self) -> Vec<ExceptionState> {
+    let mut state = self.state.lock().unwrap();
+    let EmulatorState { dm, pes } = &mut *state;
+    for pe in pes.iter_mut() {
+      pe.run(dm, self.config.insns_per_poll);
+    }
+    pes
+      .iter()
+      .map(|x| ExceptionState::from_raw(x.exception.pc, x.exception.code, x.exception.data))
+      .collect()
+  }
+}
+
+impl Backend for Emulator {
+  fn num_pe(&self) -> Result<u32> {
+    Ok(self.config.num_pe)
+  }
+
+  fn load_code(&self, pe_index: u32, offset: u32, code: &[u8]) -> Result<()> {
+    self.with_pe(pe_index, |pe| {
+      let offset = offset as usize;
+      let target = offset
+        .checked_add(code.len())
+        .and_then(|end| pe.code.get_mut(offset..end))
+        .ok_or_else(|| anyhow::anyhow!("code does not fit in code memory"))?;
+      target.copy_from_slice(code);
+      Ok(())
+    })?
+  }
+
+  fn start(&self, pe_index: u32, pc: u32) -> Result<()> {
+    self.with_pe(pe_index, |pe| pe.start(pc))
+  }
+
+  fn stop(&self, pe_index: u32) -> Result<()> {
+    self.with_pe(pe_index, |pe| pe.stop())
+  }
+
+  async fn read_exception_state(&self) -> Result<Vec<ExceptionState>> {
+    // Go through the timer rather than `yield_now` so that tasks polling other PEs, including ones
+    // spawned from outside the runtime, get a chance to run.
+    tokio::time::sleep(std::time::Duration::ZERO).await;
+    Ok(self.poll_exception_state())
+  }
+
+  fn read_perf_counters(&self, pe_index: u32) -> Result<PerfCounters> {
+    self.with_pe(pe_index, |pe| pe.perf.clone())
+  }
+
+  fn has_register_access(&self) -> bool {
+    true
+  }
+
+  fn read_registers(&self, pe_index: u32) -> Result<[u64; 11]> {
+    self.with_pe(pe_index, |pe| pe.regs)
+  }
+
+  fn write_register(&self, pe_index: u32, reg: u32, value: u64) -> Result<()> {
+    self.with_pe(pe_index, |pe| {
+      if pe.running {
+        anyhow::bail!("cannot write registers of a running pe");
+      }
+      *pe
+        .regs
+        .get_mut(reg as usize)
+        .ok_or_else(|| anyhow::anyhow!("invalid register index {}", reg))? = value;
+      Ok(())
+    })?
+  }
+
+  fn dma_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+    let state = self.state.lock().unwrap();
+    let offset = offset as usize;
+    let src = offset
+      .checked_add(output.len())
+      .and_then(|end| state.dm.get(offset..end))
+      .ok_or_else(|| anyhow::anyhow!("data memory read out of bounds"))?;
+    output.copy_from_slice(src);
+    Ok(())
+  }
+
+  fn dma_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let offset = offset as usize;
+    let dst = offset
+      .checked_add(data.len())
+      .and_then(|end| state.dm.get_mut(offset..end))
+      .ok_or_else(|| anyhow::anyhow!("data memory write out of bounds"))?;
+    dst.copy_from_slice(data);
+    Ok(())
+  }
+
+  fn mmio_read(&self, offset: u32, output: &mut [u8]) -> Result<()> {
+    self.dma_read(offset, output)
+  }
+
+  fn mmio_write(&self, offset: u32, data: &[u8]) -> Result<()> {
+    self.dma_write(offset, data)
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use std::sync::Arc;
+
+  use bumpalo::Bump;
+
+  use super::*;
+  use crate::{
+    device::{Device, RunOptions, RunResult},
+    exception::ExceptionCode,
+    linker::{
+      global_linker::{GlobalLinker, GlobalLinkerConfig},
+      image::{HostPlatform, Image, TargetMachine},
+    },
+    state::MachineState,
+  };
+
+  /// Links the programs in `testdata/emulator.ll`, keeping only `dce_roots` if given.
+  pub(crate) fn link_test_image(dce_roots: Option<&[&str]>) -> Image {
+    link(
+      "emulator.o",
+      include_bytes!("../../testdata/emulator.o"),
+      dce_roots,
+    )
+  }
+
+  /// Links `testdata/counter.ll`, whose program increments a global.
+  pub(crate) fn link_counter_image() -> Image {
+    link(
+      "counter.o",
+      include_bytes!("../../testdata/counter.o"),
+      None,
+    )
+  }
+
+  fn link(name: &str, object: &[u8], dce_roots: Option<&[&str]>) -> Image {
+    let config = GlobalLinkerConfig {
+      target_machine: TargetMachine {
+        helpers: [("wbpf_machine_get_core_index".to_string(), 1)].into(),
+      },
+      host_platform: HostPlatform {
+        helpers: [
+          ("wbpf_host_complete".to_string(), 1024),
+          ("host_add".to_string(), 1025),
+        ]
+        .into(),
+        data_offset: 0x100,
+      },
+      dce_roots: dce_roots.map(|x| x.iter().map(|x| x.to_string()).collect()),
+      ..Default::default()
+    };
+    let bump = Bump::new();
+    let mut linker = GlobalLinker::new(&bump, config).unwrap();
+    linker.add_object(name, object).unwrap();
+    linker.emit().unwrap()
+  }
+
+  pub(crate) fn test_device(num_pe: u32) -> Device<Emulator> {
+    let config = EmulatorConfig {
+      num_pe,
+      ..Default::default()
+    };
+    Device::new(Arc::new(Emulator::new(config))).unwrap()
+  }
+
+  pub(crate) fn state(yaml: &str) -> MachineState {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  async fn run(device: &Device<Emulator>, state: &MachineState, pe_index: u32) -> RunResult {
+    let image = link_test_image(None);
+    device
+      .run(&image, state, pe_index, &RunOptions::default())
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn runs_add_example() {
+    let state = state(
+      r#"
+buffers:
+  out: { fill: "ff", size: 8 }
+registers: [0, 40, 2, { buffer: out }, 0, 0, 0, 0, 0, 0, { stack: 0x100 }]
+entryPoint: add
+dump: [{ buffer: out }]
+"#,
+    );
+    let result = run(&test_device(1), &state, 0).await;
+    assert_eq!(
+      result.exception.code,
+      ExceptionCode::HelperCall { index: 1024 }
+    );
+    assert_eq!(result.location.as_deref(), Some("emulator.o:add+0x10"));
+    assert_eq!(result.dm_snapshots.len(), 1);
+    assert_eq!(
+      result.dm_snapshots[0].data,
+      [42, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]
+    );
+  }
+
+  #[tokio::test]
+  async fn services_core_index_helper() {
+    let state = state(
+      r#"
+buffers:
+  out: { fill: "ff", size: 8 }
+registers: [0, { buffer: out }, 0, 0, 0, 0, 0, 0, 0, 0, { stack: 0x100 }]
+entryPoint: core_index
+dump: [{ buffer: out }]
+"#,
+    );
+    let device = test_device(2);
+    for pe_index in 0..2 {
+      let result = run(&device, &state, pe_index).await;
+      assert_eq!(result.return_value, Some(pe_index as u64));
+      assert_eq!(result.dm_snapshots[0].data, (pe_index as u64).to_le_bytes());
+    }
+  }
+
+  #[tokio::test]
+  async fn faults_on_misaligned_load() {
+    let state = state(
+      r#"
+registers: [0, 0x2003, 0x2000, 0, 0, 0, 0, 0, 0, 0, 0x3000]
+entryPoint: load
+"#,
+    );
+    let result = run(&test_device(1), &state, 0).await;
+    assert_eq!(
+      result.exception.code,
+      ExceptionCode::LoadFault { addr: 0x2003 }
+    );
+    assert!(result.exception.code.is_fault());
+    assert_eq!(result.location.as_deref(), Some("emulator.o:load+0x0"));
+    assert_eq!(result.perf.commits, 13);
+  }
+}
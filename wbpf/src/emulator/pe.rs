@@ -0,0 +1,493 @@
+use crate::{
+  exception::{
+    CAUSE_DIV_BY_ZERO, CAUSE_HELPER_CALL, CAUSE_ILLEGAL_INSN, CAUSE_INTR, CAUSE_LOAD_FAULT,
+    CAUSE_NONE, CAUSE_STORE_FAULT, EXC_STOP,
+  },
+  linker::ebpf::{self, get_insn, Insn, INSN_SIZE},
+  perf::PerfCounters,
+};
+
+use super::DATA_MEMORY_SIZE;
+
+/// Helper index of `wbpf_machine_get_core_index`, serviced by the PE itself.
+const HELPER_GET_CORE_INDEX: i32 = 1;
+
+/// Exception state of a PE in the raw form reported by the hardware, decoded with
+/// `ExceptionState::from_raw`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RawExceptionState {
+  pub pc: u32,
+  pub code: u32,
+  pub data: u64,
+}
+
+/// Cause and exception data of an instruction that stopped the PE.
+#[derive(Debug)]
+struct Trap {
+  cause: u32,
+  data: u64,
+}
+
+impl Trap {
+  fn illegal_insn(insn: u64) -> Self {
+    Self {
+      cause: CAUSE_ILLEGAL_INSN,
+      data: insn,
+    }
+  }
+
+  fn load_fault(addr: u64) -> Self {
+    Self {
+      cause: CAUSE_LOAD_FAULT,
+      data: addr,
+    }
+  }
+
+  fn store_fault(addr: u64) -> Self {
+    Self {
+      cause: CAUSE_STORE_FAULT,
+      data: addr,
+    }
+  }
+
+  fn divide_by_zero() -> Self {
+    Self {
+      cause: CAUSE_DIV_BY_ZERO,
+      data: 0,
+    }
+  }
+
+  fn helper_call(index: u32) -> Self {
+    Self {
+      cause: CAUSE_HELPER_CALL,
+      data: index as u64,
+    }
+  }
+}
+
+pub(crate) struct ProcessingElement {
+  pub index: u32,
+  pub code: Vec<u8>,
+  pub regs: [u64; 11],
+  pub pc: u32,
+  pub running: bool,
+  pub exception: RawExceptionState,
+  pub perf: PerfCounters,
+}
+
+impl ProcessingElement {
+  pub fn new(index: u32, code_memory_size: usize) -> Self {
+    Self {
+      index,
+      code: vec![0u8; code_memory_size],
+      regs: [0; 11],
+      pc: 0,
+      running: false,
+      exception: RawExceptionState {
+        pc: 0,
+        code: EXC_STOP | CAUSE_NONE,
+        data: 0,
+      },
+      perf: PerfCounters {
+        cycles: 0,
+        commits: 0,
+      },
+    }
+  }
+
+  pub fn start(&mut self, pc: u32) {
+    self.pc = pc;
+    self.running = true;
+    self.exception = RawExceptionState {
+      pc,
+      code: 0,
+      data: 0,
+    };
+  }
+
+  pub fn stop(&mut self) {
+    self.running = false;
+    self.exception = RawExceptionState {
+      pc: self.pc,
+      code: EXC_STOP | CAUSE_INTR,
+      data: 0,
+    };
+  }
+
+  /// Executes at most `budget` instructions, stopping early on a trap.
+  pub fn run(&mut self, dm: &mut [u8], budget: u64) {
+    for _ in 0..budget {
+      if !self.running {
+        break;
+      }
+      let pc = self.pc;
+      match self.step(dm) {
+        Ok(()) => {
+          self.perf.cycles += 1;
+          self.perf.commits += 1;
+        }
+        Err(trap) => {
+          self.running = false;
+          self.exception = RawExceptionState {
+            pc,
+            code: EXC_STOP | trap.cause,
+            data: trap.data,
+          };
+          self.perf.cycles += 1;
+        }
+      }
+    }
+  }
+
+  fn fetch(&self, pc: u32) -> Option<Insn> {
+    let pc = pc as usize;
+    if pc & (INSN_SIZE - 1) != 0 || pc + INSN_SIZE > self.code.len() {
+      return None;
+    }
+    Some(get_insn(&self.code[pc..pc + INSN_SIZE], 0))
+  }
+
+  fn raw_insn(&self, pc: u32) -> u64 {
+    let pc = pc as usize;
+    self
+      .code
+      .get(pc..pc + INSN_SIZE)
+      .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+      .unwrap_or(0)
+  }
+
+  fn step(&mut self, dm: &mut [u8]) -> Result<(), Trap> {
+    let pc = self.pc;
+    let raw = self.raw_insn(pc);
+    let illegal = || Trap::illegal_insn(raw);
+    let insn = self.fetch(pc).ok_or_else(illegal)?;
+    let dst = insn.dst as usize;
+    let src = insn.src as usize;
+    if dst > 10 || src > 10 {
+      return Err(illegal());
+    }
+    let imm = insn.imm as i64 as u64;
+    let mut next_pc = pc + INSN_SIZE as u32;
+
+    match insn.opc & ebpf::BPF_CLS_MASK {
+      ebpf::BPF_ALU64 | ebpf::BPF_ALU => {
+        let is64 = insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_ALU64;
+        let op = insn.opc & ebpf::BPF_ALU_OP_MASK;
+        if op == ebpf::BPF_END {
+          if is64 {
+            return Err(illegal());
+          }
+          let value = self.regs[dst];
+          let big = insn.opc & ebpf::BPF_X != 0;
+          self.regs[dst] = match (insn.imm, big) {
+            (16, false) => value as u16 as u64,
+            (32, false) => value as u32 as u64,
+            (64, false) => value,
+            (16, true) => (value as u16).swap_bytes() as u64,
+            (32, true) => (value as u32).swap_bytes() as u64,
+            (64, true) => value.swap_bytes(),
+            _ => return Err(illegal()),
+          };
+        } else {
+          let lhs = self.regs[dst];
+          let rhs = if insn.opc & ebpf::BPF_X != 0 {
+            self.regs[src]
+          } else {
+            imm
+          };
+          let result = if is64 {
+            alu64(op, lhs, rhs).ok_or_else(illegal)??
+          } else {
+            alu32(op, lhs as u32, rhs as u32).ok_or_else(illegal)?? as u64
+          };
+          self.regs[dst] = result;
+        }
+      }
+      ebpf::BPF_LD => {
+        if insn.opc != ebpf::LD_DW_IMM {
+          return Err(illegal());
+        }
+        let hi = self
+          .fetch(next_pc)
+          .ok_or_else(|| Trap::illegal_insn(self.raw_insn(next_pc)))?;
+        self.regs[dst] = (insn.imm as u32 as u64) | ((hi.imm as u32 as u64) << 32);
+        next_pc += INSN_SIZE as u32;
+      }
+      ebpf::BPF_LDX => {
+        if insn.opc & 0xe0 != ebpf::BPF_MEM {
+          return Err(illegal());
+        }
+        let addr = self.regs[src].wrapping_add(insn.off as i64 as u64);
+        self.regs[dst] = load(dm, addr, access_size(insn.opc)).ok_or(Trap::load_fault(addr))?;
+      }
+      ebpf::BPF_ST | ebpf::BPF_STX => {
+        let addr = self.regs[dst].wrapping_add(insn.off as i64 as u64);
+        let size = access_size(insn.opc);
+        let value = if insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_ST {
+          imm
+        } else {
+          self.regs[src]
+        };
+        match insn.opc & 0xe0 {
+          ebpf::BPF_MEM => {}
+          ebpf::BPF_XADD if insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_STX && size >= 4 => {
+            let old = load(dm, addr, size).ok_or(Trap::load_fault(addr))?;
+            store(dm, addr, size, old.wrapping_add(value)).ok_or(Trap::store_fault(addr))?;
+            self.pc = next_pc;
+            return Ok(());
+          }
+          _ => return Err(illegal()),
+        }
+        store(dm, addr, size, value).ok_or(Trap::store_fault(addr))?;
+      }
+      ebpf::BPF_JMP => match insn.opc {
+        ebpf::JA => match insn.src {
+          0 => next_pc = branch_target(pc, insn.off),
+          // Return: pop the (r10 << 32 | pc) record at r10.
+          1 => {
+            let addr = self.regs[10];
+            let record = load(dm, addr, 8).ok_or(Trap::load_fault(addr))?;
+            next_pc = record as u32;
+            self.regs[10] = record >> 32;
+          }
+          // Call: push a return record `imm` bytes below r10 and jump.
+          2 => {
+            let new_sp = self.regs[10].wrapping_add(imm);
+            let record = (self.regs[10] << 32) | next_pc as u64;
+            store(dm, new_sp, 8, record).ok_or(Trap::store_fault(new_sp))?;
+            self.regs[10] = new_sp;
+            next_pc = branch_target(pc, insn.off);
+          }
+          _ => return Err(illegal()),
+        },
+        ebpf::CALL => {
+          if insn.src != 0 {
+            return Err(illegal());
+          }
+          if insn.imm == HELPER_GET_CORE_INDEX {
+            self.regs[0] = self.index as u64;
+          } else {
+            return Err(Trap::helper_call(insn.imm as u32));
+          }
+        }
+        _ => {
+          let lhs = self.regs[dst];
+          let rhs = if insn.opc & ebpf::BPF_X != 0 {
+            self.regs[src]
+          } else {
+            imm
+          };
+          let taken = match insn.opc & ebpf::BPF_ALU_OP_MASK {
+            ebpf::BPF_JEQ => lhs == rhs,
+            ebpf::BPF_JGT => lhs > rhs,
+            ebpf::BPF_JGE => lhs >= rhs,
+            ebpf::BPF_JSET => lhs & rhs != 0,
+            ebpf::BPF_JNE => lhs != rhs,
+            ebpf::BPF_JSGT => (lhs as i64) > (rhs as i64),
+            ebpf::BPF_JSGE => (lhs as i64) >= (rhs as i64),
+            ebpf::BPF_JLT => lhs < rhs,
+            ebpf::BPF_JLE => lhs <= rhs,
+            ebpf::BPF_JSLT => (lhs as i64) < (rhs as i64),
+            ebpf::BPF_JSLE => (lhs as i64) <= (rhs as i64),
+            _ => return Err(illegal()),
+          };
+          if taken {
+            next_pc = branch_target(pc, insn.off);
+          }
+        }
+      },
+      _ => return Err(illegal()),
+    }
+
+    self.pc = next_pc;
+    Ok(())
+  }
+}
+
+fn branch_target(pc: u32, off: i16) -> u32 {
+  (pc as i64 + (off as i64 + 1) * INSN_SIZE as i64) as u32
+}
+
+fn access_size(opc: u8) -> usize {
+  match opc & 0x18 {
+    ebpf::BPF_B => 1,
+    ebpf::BPF_H => 2,
+    ebpf::BPF_W => 4,
+    _ => 8,
+  }
+}
+
+/// Decodes a data memory address. Only the low bits selecting a byte within the data memory are
+/// decoded, so addresses wrap around; misaligned accesses fault.
+fn decode_address(addr: u64, size: usize) -> Option<usize> {
+  if addr & (size as u64 - 1) != 0 {
+    return None;
+  }
+  Some(addr as usize & (DATA_MEMORY_SIZE - 1))
+}
+
+fn load(dm: &[u8], addr: u64, size: usize) -> Option<u64> {
+  let addr = decode_address(addr, size)?;
+  let mut buf = [0u8; 8];
+  buf[..size].copy_from_slice(&dm[addr..addr + size]);
+  Some(u64::from_le_bytes(buf))
+}
+
+fn store(dm: &mut [u8], addr: u64, size: usize, value: u64) -> Option<()> {
+  let addr = decode_address(addr, size)?;
+  dm[addr..addr + size].copy_from_slice(&value.to_le_bytes()[..size]);
+  Some(())
+}
+
+/// Returns `None` for an unknown operation and `Some(Err(_))` on a trap.
+fn alu64(op: u8, lhs: u64, rhs: u64) -> Option<Result<u64, Trap>> {
+  Some(Ok(match op {
+    ebpf::BPF_ADD => lhs.wrapping_add(rhs),
+    ebpf::BPF_SUB => lhs.wrapping_sub(rhs),
+    ebpf::BPF_MUL => lhs.wrapping_mul(rhs),
+    ebpf::BPF_DIV => match lhs.checked_div(rhs) {
+      Some(x) => x,
+      None => return Some(Err(Trap::divide_by_zero())),
+    },
+    ebpf::BPF_MOD => match lhs.checked_rem(rhs) {
+      Some(x) => x,
+      None => return Some(Err(Trap::divide_by_zero())),
+    },
+    ebpf::BPF_OR => lhs | rhs,
+    ebpf::BPF_AND => lhs & rhs,
+    ebpf::BPF_XOR => lhs ^ rhs,
+    ebpf::BPF_LSH => lhs.wrapping_shl(rhs as u32),
+    ebpf::BPF_RSH => lhs.wrapping_shr(rhs as u32),
+    ebpf::BPF_ARSH => (lhs as i64).wrapping_shr(rhs as u32) as u64,
+    ebpf::BPF_NEG => (lhs as i64).wrapping_neg() as u64,
+    ebpf::BPF_MOV => rhs,
+    _ => return None,
+  }))
+}
+
+fn alu32(op: u8, lhs: u32, rhs: u32) -> Option<Result<u32, Trap>> {
+  Some(Ok(match op {
+    ebpf::BPF_ADD => lhs.wrapping_add(rhs),
+    ebpf::BPF_SUB => lhs.wrapping_sub(rhs),
+    ebpf::BPF_MUL => lhs.wrapping_mul(rhs),
+    ebpf::BPF_DIV => match lhs.checked_div(rhs) {
+      Some(x) => x,
+      None => return Some(Err(Trap::divide_by_zero())),
+    },
+    ebpf::BPF_MOD => match lhs.checked_rem(rhs) {
+      Some(x) => x,
+      None => return Some(Err(Trap::divide_by_zero())),
+    },
+    ebpf::BPF_OR => lhs | rhs,
+    ebpf::BPF_AND => lhs & rhs,
+    ebpf::BPF_XOR => lhs ^ rhs,
+    ebpf::BPF_LSH => lhs.wrapping_shl(rhs),
+    ebpf::BPF_RSH => lhs.wrapping_shr(rhs),
+    ebpf::BPF_ARSH => (lhs as i32).wrapping_shr(rhs) as u32,
+    ebpf::BPF_NEG => (lhs as i32).wrapping_neg() as u32,
+    ebpf::BPF_MOV => rhs,
+    _ => return None,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    exception::{ExceptionCode, ExceptionState},
+    linker::ebpf::{CALL, DIV64_IMM, JA, LD_DW_REG, MOV64_IMM, ST_W_REG},
+  };
+
+  fn insn(opc: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+      opc,
+      dst,
+      src,
+      off,
+      imm,
+    }
+  }
+
+  /// Runs `code` from pc 0 with r1 = `r1` and decodes the resulting exception state.
+  fn run(code: &[Insn], r1: u64) -> ExceptionState {
+    let mut pe = ProcessingElement::new(0, 4096);
+    for (i, x) in code.iter().enumerate() {
+      pe.code[i * INSN_SIZE..(i + 1) * INSN_SIZE].copy_from_slice(&x.to_array());
+    }
+    pe.regs[1] = r1;
+    pe.start(0);
+    pe.run(&mut vec![0u8; DATA_MEMORY_SIZE], 100);
+    let raw = pe.exception;
+    ExceptionState::from_raw(raw.pc, raw.code, raw.data)
+  }
+
+  #[test]
+  fn reports_stopped_after_reset() {
+    let raw = ProcessingElement::new(0, 4096).exception;
+    assert_eq!(
+      ExceptionCode::from_raw(raw.code, raw.data),
+      ExceptionCode::Stopped
+    );
+  }
+
+  #[test]
+  fn reports_running_and_interrupted() {
+    let mut pe = ProcessingElement::new(0, 4096);
+    pe.code[..INSN_SIZE].copy_from_slice(&insn(JA, 0, 0, -1, 0).to_array());
+    pe.start(0);
+    pe.run(&mut vec![0u8; DATA_MEMORY_SIZE], 10);
+    let raw = pe.exception;
+    assert_eq!(
+      ExceptionCode::from_raw(raw.code, raw.data),
+      ExceptionCode::Running
+    );
+    pe.stop();
+    let raw = pe.exception;
+    assert_eq!(
+      ExceptionCode::from_raw(raw.code, raw.data),
+      ExceptionCode::Interrupted
+    );
+  }
+
+  #[test]
+  fn reports_illegal_instruction() {
+    let es = run(&[insn(MOV64_IMM, 0, 0, 0, 0), insn(0xff, 0, 0, 0, 0)], 0);
+    assert_eq!(es.pc, 8);
+    assert_eq!(es.code, ExceptionCode::IllegalInstruction { insn: 0xff });
+  }
+
+  #[test]
+  fn reports_load_fault() {
+    let es = run(&[insn(LD_DW_REG, 0, 1, 4, 0)], 0x1000);
+    assert_eq!(es.code, ExceptionCode::LoadFault { addr: 0x1004 });
+  }
+
+  #[test]
+  fn reports_store_fault() {
+    let es = run(&[insn(ST_W_REG, 1, 0, 0, 0)], 0x1002);
+    assert_eq!(es.code, ExceptionCode::StoreFault { addr: 0x1002 });
+  }
+
+  #[test]
+  fn reports_divide_by_zero() {
+    let es = run(&[insn(DIV64_IMM, 1, 0, 0, 0)], 1);
+    assert_eq!(es.code, ExceptionCode::DivideByZero);
+  }
+
+  #[test]
+  fn reports_helper_call() {
+    let es = run(&[insn(CALL, 0, 0, 0, 1024)], 0);
+    assert_eq!(es.code, ExceptionCode::HelperCall { index: 1024 });
+  }
+
+  #[test]
+  fn services_core_index_helper() {
+    let mut pe = ProcessingElement::new(3, 4096);
+    pe.code[..INSN_SIZE].copy_from_slice(&insn(CALL, 0, 0, 0, 1).to_array());
+    pe.code[INSN_SIZE..2 * INSN_SIZE].copy_from_slice(&insn(JA, 0, 0, -1, 0).to_array());
+    pe.start(0);
+    pe.run(&mut vec![0u8; DATA_MEMORY_SIZE], 10);
+    assert!(pe.running);
+    assert_eq!(pe.regs[0], 3);
+  }
+}
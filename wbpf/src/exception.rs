@@ -0,0 +1,200 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Exception code bit set whenever the PE is not running. The low bits hold the cause of the stop
+/// and the exception data a cause-specific value.
+pub(crate) const EXC_STOP: u32 = 0x80000000;
+
+/// Not started since reset.
+pub(crate) const CAUSE_NONE: u32 = 0;
+/// Data is the raw instruction.
+pub(crate) const CAUSE_ILLEGAL_INSN: u32 = 1;
+/// Data is the address.
+pub(crate) const CAUSE_LOAD_FAULT: u32 = 2;
+/// Data is the address.
+pub(crate) const CAUSE_STORE_FAULT: u32 = 3;
+pub(crate) const CAUSE_DIV_BY_ZERO: u32 = 4;
+/// Data is the helper index.
+pub(crate) const CAUSE_HELPER_CALL: u32 = 5;
+/// Stopped by the host.
+pub(crate) const CAUSE_INTR: u32 = 7;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExceptionState {
+  pub pc: u32,
+  pub code: ExceptionCode,
+}
+
+/// Decoded exception code of a PE, together with the exception data interpreted for its kind.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum ExceptionCode {
+  /// The PE is executing code.
+  Running,
+  /// The PE is idle and has not been started since reset.
+  Stopped,
+  /// The PE was stopped by the host.
+  Interrupted,
+  /// The PE fetched an instruction it cannot execute.
+  IllegalInstruction { insn: u64 },
+  /// A load accessed an invalid address.
+  LoadFault { addr: u64 },
+  /// A store accessed an invalid address.
+  StoreFault { addr: u64 },
+  /// A division or modulo instruction had a zero divisor.
+  DivideByZero,
+  /// The PE called a helper that has to be serviced by the host.
+  HelperCall { index: u32 },
+  /// A code this version of the library does not know about.
+  Unknown { code: u32, data: u64 },
+}
+
+impl ExceptionState {
+  pub fn from_raw(pc: u32, code: u32, data: u64) -> Self {
+    Self {
+      pc,
+      code: ExceptionCode::from_raw(code, data),
+    }
+  }
+}
+
+impl ExceptionCode {
+  pub fn from_raw(code: u32, data: u64) -> Self {
+    if code & EXC_STOP == 0 {
+      return ExceptionCode::Running;
+    }
+    match code & !EXC_STOP {
+      CAUSE_NONE => ExceptionCode::Stopped,
+      CAUSE_INTR => ExceptionCode::Interrupted,
+      CAUSE_ILLEGAL_INSN => ExceptionCode::IllegalInstruction { insn: data },
+      CAUSE_LOAD_FAULT => ExceptionCode::LoadFault { addr: data },
+      CAUSE_STORE_FAULT => ExceptionCode::StoreFault { addr: data },
+      CAUSE_DIV_BY_ZERO => ExceptionCode::DivideByZero,
+      CAUSE_HELPER_CALL => ExceptionCode::HelperCall { index: data as u32 },
+      _ => ExceptionCode::Unknown { code, data },
+    }
+  }
+
+  pub fn is_running(&self) -> bool {
+    matches!(self, ExceptionCode::Running)
+  }
+
+  /// Whether the PE stopped because of an error in the program.
+  pub fn is_fault(&self) -> bool {
+    matches!(
+      self,
+      ExceptionCode::IllegalInstruction { .. }
+        | ExceptionCode::LoadFault { .. }
+        | ExceptionCode::StoreFault { .. }
+        | ExceptionCode::DivideByZero
+    )
+  }
+}
+
+impl Display for ExceptionCode {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      ExceptionCode::Running => write!(f, "running"),
+      ExceptionCode::Stopped => write!(f, "stopped"),
+      ExceptionCode::Interrupted => write!(f, "interrupted"),
+      ExceptionCode::IllegalInstruction { insn } => {
+        write!(f, "illegal instruction {:#018x}", insn)
+      }
+      ExceptionCode::LoadFault { addr } => write!(f, "load from invalid address {:#x}", addr),
+      ExceptionCode::StoreFault { addr } => write!(f, "store to invalid address {:#x}", addr),
+      ExceptionCode::DivideByZero => write!(f, "divide by zero"),
+      ExceptionCode::HelperCall { index } => write!(f, "call to helper {}", index),
+      ExceptionCode::Unknown { code, data } => {
+        write!(f, "unknown exception {:#x} (data {:#x})", code, data)
+      }
+    }
+  }
+}
+
+impl Display for ExceptionState {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{} at pc {:#x}", self.code, self.pc)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode(cause: u32, data: u64) -> ExceptionCode {
+    ExceptionCode::from_raw(EXC_STOP | cause, data)
+  }
+
+  #[test]
+  fn decodes_running() {
+    assert_eq!(ExceptionCode::from_raw(0, 0), ExceptionCode::Running);
+    assert_eq!(ExceptionCode::from_raw(5, 42), ExceptionCode::Running);
+  }
+
+  #[test]
+  fn decodes_stopped() {
+    assert_eq!(decode(CAUSE_NONE, 0), ExceptionCode::Stopped);
+  }
+
+  #[test]
+  fn decodes_interrupted() {
+    assert_eq!(decode(CAUSE_INTR, 0), ExceptionCode::Interrupted);
+    assert_eq!(
+      ExceptionCode::from_raw(0x80000007, 0),
+      ExceptionCode::Interrupted
+    );
+  }
+
+  #[test]
+  fn decodes_illegal_instruction() {
+    let code = decode(CAUSE_ILLEGAL_INSN, 0xff00_0000_0000_00ff);
+    assert_eq!(
+      code,
+      ExceptionCode::IllegalInstruction {
+        insn: 0xff00_0000_0000_00ff
+      }
+    );
+    assert!(code.is_fault());
+  }
+
+  #[test]
+  fn decodes_load_fault() {
+    let code = decode(CAUSE_LOAD_FAULT, 0x1003);
+    assert_eq!(code, ExceptionCode::LoadFault { addr: 0x1003 });
+    assert!(code.is_fault());
+  }
+
+  #[test]
+  fn decodes_store_fault() {
+    let code = decode(CAUSE_STORE_FAULT, 0x2001);
+    assert_eq!(code, ExceptionCode::StoreFault { addr: 0x2001 });
+    assert!(code.is_fault());
+  }
+
+  #[test]
+  fn decodes_divide_by_zero() {
+    let code = decode(CAUSE_DIV_BY_ZERO, 0);
+    assert_eq!(code, ExceptionCode::DivideByZero);
+    assert!(code.is_fault());
+  }
+
+  #[test]
+  fn decodes_helper_call() {
+    let code = decode(CAUSE_HELPER_CALL, 1024);
+    assert_eq!(code, ExceptionCode::HelperCall { index: 1024 });
+    assert!(!code.is_fault());
+  }
+
+  #[test]
+  fn decodes_unknown_cause() {
+    assert_eq!(
+      decode(6, 42),
+      ExceptionCode::Unknown {
+        code: 0x80000006,
+        data: 42
+      }
+    );
+  }
+}
@@ -0,0 +1,64 @@
+use anyhow::Result;
+
+use crate::{backend::Backend, dm::DataMemory, types::FnvIndexMap};
+
+/// Name of the host helper that ends a run.
+pub const HOST_COMPLETE: &str = "wbpf_host_complete";
+
+/// What the run loop should do after a host helper returns.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HelperAction {
+  /// Write the value to r0 and resume the PE after the call.
+  Return(u64),
+  /// End the run, leaving the PE stopped at the call.
+  Complete,
+}
+
+/// Arguments and resources available to a host helper.
+pub struct HelperContext<'a, B: Backend> {
+  pub pe_index: u32,
+  /// r1-r5 at the time of the call.
+  pub args: [u64; 5],
+  pub dm: &'a DataMemory<B>,
+}
+
+pub type HelperFn<B> = Box<dyn FnMut(&mut HelperContext<B>) -> Result<HelperAction> + Send>;
+
+/// Host implementations of helpers, keyed by the helper names in `HostPlatform.helpers`.
+///
+/// Only backends with register access, i.e. the emulator, can pass arguments to and return values
+/// from host helpers. See `Device::run_with_helpers`.
+pub struct HelperRegistry<B: Backend> {
+  helpers: FnvIndexMap<String, HelperFn<B>>,
+}
+
+impl<B: Backend> Default for HelperRegistry<B> {
+  fn default() -> Self {
+    let mut registry = Self {
+      helpers: Default::default(),
+    };
+    registry.register(HOST_COMPLETE, |_| Ok(HelperAction::Complete));
+    registry
+  }
+}
+
+impl<B: Backend> HelperRegistry<B> {
+  /// Creates a registry that only knows about `wbpf_host_complete`.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers a helper, replacing any previous registration with the same name.
+  pub fn register(
+    &mut self,
+    name: &str,
+    f: impl FnMut(&mut HelperContext<B>) -> Result<HelperAction> + Send + 'static,
+  ) -> &mut Self {
+    self.helpers.insert(name.to_string(), Box::new(f));
+    self
+  }
+
+  pub fn get_mut(&mut self, name: &str) -> Option<&mut HelperFn<B>> {
+    self.helpers.get_mut(name)
+  }
+}
@@ -1,6 +1,13 @@
+pub mod backend;
 pub mod device;
 pub mod dm;
+pub mod dm_alloc;
+pub mod emulator;
+pub mod exception;
+pub mod helper;
 pub mod linker;
 pub mod perf;
+pub mod scheduler;
+pub mod state;
 pub mod types;
 pub mod uapi;
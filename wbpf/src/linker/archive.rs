@@ -0,0 +1,65 @@
+use anyhow::Result;
+use goblin::{
+  archive::Archive,
+  elf::Elf,
+  elf64::{section_header::SHN_UNDEF, sym::STB_LOCAL},
+};
+
+use super::elf_ext::StrtabExt;
+
+pub fn is_archive(file: &[u8]) -> bool {
+  file.starts_with(goblin::archive::MAGIC)
+}
+
+/// An object file in a static archive.
+pub struct ArchiveMember<'a> {
+  /// `archive(member)`.
+  pub name: String,
+  pub raw: &'a [u8],
+  /// Non-local symbols defined by the object.
+  pub defined: Vec<&'a str>,
+}
+
+/// Splits a static archive into its members, in archive order.
+pub fn parse_archive<'a>(name: &str, archive_file: &'a [u8]) -> Result<Vec<ArchiveMember<'a>>> {
+  let archive = Archive::parse(archive_file)
+    .map_err(|e| anyhow::anyhow!("failed to parse archive {}: {}", name, e))?;
+  let mut members = Vec::with_capacity(archive.len());
+  for i in 0..archive.len() {
+    let member = archive.get_at(i).unwrap();
+    let member_name = format!("{}({})", name, member.extended_name());
+    let raw = archive_file
+      .get(member.offset as usize..member.offset as usize + member.size())
+      .ok_or_else(|| anyhow::anyhow!("archive member {} out of bounds", member_name))?;
+    let elf = Elf::parse(raw)
+      .map_err(|e| anyhow::anyhow!("failed to parse archive member {}: {}", member_name, e))?;
+    let defined = defined_symbols(&elf)?;
+    members.push(ArchiveMember {
+      name: member_name,
+      raw,
+      defined,
+    });
+  }
+  Ok(members)
+}
+
+/// Non-local symbols defined by `elf`.
+pub fn defined_symbols<'a>(elf: &Elf<'a>) -> Result<Vec<&'a str>> {
+  elf
+    .syms
+    .iter()
+    .filter(|sym| sym.st_bind() != STB_LOCAL && sym.st_shndx != SHN_UNDEF as usize)
+    .map(|sym| elf.shdr_strtab.get_at_result(sym.st_name))
+    .collect()
+}
+
+/// Symbols referenced but not defined by `elf`.
+pub fn undefined_symbols<'a>(elf: &Elf<'a>) -> Result<Vec<&'a str>> {
+  elf
+    .syms
+    .iter()
+    .filter(|sym| sym.st_bind() != STB_LOCAL && sym.st_shndx == SHN_UNDEF as usize)
+    .map(|sym| elf.shdr_strtab.get_at_result(sym.st_name))
+    .filter(|x| !matches!(x, Ok("")))
+    .collect()
+}
@@ -0,0 +1,190 @@
+//! On-disk cache of local linker results, keyed by object content and linker version.
+
+use std::{
+  hash::Hasher,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use bumpalo::Bump;
+use fnv::FnvHasher;
+use goblin::elf::Reloc;
+use prost::Message;
+
+use super::{
+  ebpf::{get_insn, Insn},
+  line_info::SourceLine,
+  local_linker::{AnnotatedInsn, Function, LocalObject},
+};
+
+mod proto {
+  include!(concat!(env!("OUT_DIR"), "/wbpf.linker.cache.rs"));
+}
+
+use proto::{CachedFunction, CachedObject, CachedReloc, CachedSourceLine};
+
+/// Bump when the output of the local linker changes.
+const CACHE_VERSION: u32 = 2;
+
+fn linker_version() -> String {
+  format!("{}+{}", env!("CARGO_PKG_VERSION"), CACHE_VERSION)
+}
+
+pub struct LinkCache {
+  dir: PathBuf,
+}
+
+impl LinkCache {
+  pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+    }
+  }
+
+  fn entry_path(&self, object_file: &[u8]) -> PathBuf {
+    let mut hasher = FnvHasher::default();
+    hasher.write(linker_version().as_bytes());
+    hasher.write(object_file);
+    self.dir.join(format!(
+      "{:016x}-{}.bin",
+      hasher.finish(),
+      object_file.len()
+    ))
+  }
+
+  /// Returns the cached result of linking `object_file`, if any.
+  pub fn load<'a>(
+    &self,
+    bump: &'a Bump,
+    name: &'a str,
+    object_file: &'a [u8],
+  ) -> Result<Option<LocalObject<'a>>> {
+    let entry = match std::fs::read(self.entry_path(object_file)) {
+      Ok(x) => CachedObject::decode(x.as_slice())?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    if entry.linker_version != linker_version() || entry.object != object_file {
+      return Ok(None);
+    }
+
+    let mut obj = LocalObject::parse(name, object_file)?;
+    obj.source_files = entry.source_files;
+    for func in entry.functions {
+      let name = &*bump.alloc_str(&func.name);
+      let code = (0..func.code.len() / 8)
+        .map(|i| get_insn(&func.code, i))
+        .zip(func.original_offsets)
+        .map(|(insn, original_offset)| AnnotatedInsn {
+          insn,
+          original_offset: original_offset as isize,
+          call_target_function: None,
+        })
+        .collect();
+      obj.functions.insert(
+        name,
+        Function {
+          name,
+          section_index: func.section_index as usize,
+          offset: func.offset as usize,
+          end_offset: func.end_offset as usize,
+          raw_code: (0..func.raw_code.len() / 8)
+            .map(|i| get_insn(&func.raw_code, i))
+            .collect(),
+          code,
+          global: func.global,
+          weak: func.weak,
+          stack_usage: func.stack_usage as usize,
+          saved_regs_size: func.saved_regs_size as usize,
+          global_linked_offset: 0,
+          lines: func
+            .lines
+            .into_iter()
+            .map(|x| SourceLine {
+              offset: x.offset,
+              file: x.file,
+              line: x.line,
+              column: x.column,
+            })
+            .collect(),
+        },
+      );
+    }
+    for reloc in entry.relocs {
+      obj.reloc.insert(
+        (reloc.func_index as usize, reloc.offset as usize),
+        Reloc {
+          r_offset: reloc.r_offset,
+          r_addend: if reloc.has_addend {
+            Some(reloc.r_addend)
+          } else {
+            None
+          },
+          r_sym: reloc.r_sym as usize,
+          r_type: reloc.r_type,
+        },
+      );
+    }
+    Ok(Some(obj))
+  }
+
+  /// Stores the result of linking `obj`.
+  pub fn store(&self, obj: &LocalObject) -> Result<()> {
+    let encode_insns = |insns: &mut dyn Iterator<Item = &Insn>| -> Vec<u8> {
+      insns.flat_map(|x| x.to_array()).collect()
+    };
+    let entry = CachedObject {
+      linker_version: linker_version(),
+      object: obj.raw.to_vec(),
+      functions: obj
+        .functions
+        .values()
+        .map(|func| CachedFunction {
+          name: func.name.to_string(),
+          section_index: func.section_index as u64,
+          offset: func.offset as u64,
+          end_offset: func.end_offset as u64,
+          raw_code: encode_insns(&mut func.raw_code.iter()),
+          code: encode_insns(&mut func.code.iter().map(|x| &x.insn)),
+          original_offsets: func.code.iter().map(|x| x.original_offset as i64).collect(),
+          global: func.global,
+          weak: func.weak,
+          stack_usage: func.stack_usage as u64,
+          saved_regs_size: func.saved_regs_size as u64,
+          lines: func
+            .lines
+            .iter()
+            .map(|x| CachedSourceLine {
+              offset: x.offset,
+              file: x.file,
+              line: x.line,
+              column: x.column,
+            })
+            .collect(),
+        })
+        .collect(),
+      relocs: obj
+        .reloc
+        .iter()
+        .map(|(&(func_index, offset), reloc)| CachedReloc {
+          func_index: func_index as u64,
+          offset: offset as u64,
+          r_offset: reloc.r_offset,
+          has_addend: reloc.r_addend.is_some(),
+          r_addend: reloc.r_addend.unwrap_or(0),
+          r_sym: reloc.r_sym as u64,
+          r_type: reloc.r_type,
+        })
+        .collect(),
+      source_files: obj.source_files.clone(),
+    };
+
+    // Write to a temporary file first so that concurrent links never see a partial entry.
+    std::fs::create_dir_all(&self.dir)?;
+    let path = self.entry_path(obj.raw);
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp_path, entry.encode_to_vec())?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+  }
+}
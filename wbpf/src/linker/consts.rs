@@ -2,3 +2,4 @@ pub const MIN_HW_REVISION: (i32, i32) = (1, 1);
 pub const MAX_HW_REVISION: (i32, i32) = (2, 0);
 pub const R_BPF_64_64: u32 = 1;
 pub const R_BPF_64_32: u32 = 10;
+pub const GRP_COMDAT: u32 = 1;
@@ -0,0 +1,342 @@
+//! Writes a linked image as an `EM_BPF` ELF executable for inspection with standard tools.
+//!
+//! `.text` holds the code image at its linked offsets and `.data` and `.bss` sit at
+//! `HostPlatform.data_offset`. Code and data live in separate address spaces on wBPF, so the two
+//! `PT_LOAD` segments may overlap in their virtual addresses.
+
+use anyhow::Result;
+use byteorder::{LittleEndian, WriteBytesExt};
+use goblin::elf64::{
+  header::{
+    EI_CLASS, EI_DATA, EI_VERSION, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_BPF, ET_EXEC, EV_CURRENT,
+    SIZEOF_EHDR, SIZEOF_IDENT,
+  },
+  program_header::{PF_R, PF_W, PF_X, PT_LOAD, SIZEOF_PHDR},
+  section_header::{
+    SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS, SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB,
+    SIZEOF_SHDR,
+  },
+  sym::{SIZEOF_SYM, STB_GLOBAL, STB_LOCAL, STT_FUNC},
+};
+
+use super::image::Image;
+
+struct Section {
+  name: &'static str,
+  sh_type: u32,
+  flags: u64,
+  addr: u64,
+  data: Vec<u8>,
+  /// Size in memory, for `SHT_NOBITS` sections.
+  size: u64,
+  link: u32,
+  info: u32,
+  align: u64,
+  entsize: u64,
+}
+
+impl Section {
+  fn new(name: &'static str, sh_type: u32, data: Vec<u8>) -> Self {
+    Self {
+      name,
+      sh_type,
+      flags: 0,
+      addr: 0,
+      size: data.len() as u64,
+      data,
+      link: 0,
+      info: 0,
+      align: 1,
+      entsize: 0,
+    }
+  }
+}
+
+struct Strtab {
+  data: Vec<u8>,
+}
+
+impl Strtab {
+  fn new() -> Self {
+    Self { data: vec![0] }
+  }
+
+  fn add(&mut self, s: &str) -> u32 {
+    let index = self.data.len() as u32;
+    self.data.extend_from_slice(s.as_bytes());
+    self.data.push(0);
+    index
+  }
+}
+
+pub fn image_to_elf(image: &Image) -> Result<Vec<u8>> {
+  let data_offset = image
+    .platform
+    .as_ref()
+    .map(|x| x.data_offset as u64)
+    .unwrap_or(0);
+
+  let mut text = Section::new(".text", SHT_PROGBITS, image.code.clone());
+  text.flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+  text.align = 8;
+  let mut data = Section::new(".data", SHT_PROGBITS, image.data.clone());
+  data.flags = (SHF_ALLOC | SHF_WRITE) as u64;
+  data.addr = data_offset;
+  data.align = 8;
+  let mut sections = vec![text, data];
+  if image.bss_size != 0 {
+    let mut bss = Section::new(".bss", SHT_NOBITS, vec![]);
+    bss.flags = (SHF_ALLOC | SHF_WRITE) as u64;
+    bss.addr = data_offset + image.data.len() as u64;
+    bss.size = image.bss_size as u64;
+    bss.align = 8;
+    sections.push(bss);
+  }
+  let data_memsz = sections[1..].iter().map(|x| x.size).sum::<u64>();
+  // Section indices count the null section.
+  let (mut symtab, strtab) = symbols(image, 1)?;
+  symtab.link = sections.len() as u32 + 2;
+  sections.push(symtab);
+  sections.push(strtab);
+
+  let mut shstrtab = Strtab::new();
+  let names = sections
+    .iter()
+    .map(|x| shstrtab.add(x.name))
+    .collect::<Vec<_>>();
+  let shstrtab_name = shstrtab.add(".shstrtab");
+  sections.push(Section::new(".shstrtab", SHT_STRTAB, shstrtab.data));
+
+  // Headers, then section contents, then section headers.
+  let phoff = SIZEOF_EHDR;
+  let mut offset = phoff + 2 * SIZEOF_PHDR;
+  let mut offsets = vec![];
+  for section in &sections {
+    offset = align_to(offset, section.align as usize);
+    offsets.push(offset as u64);
+    offset += section.data.len();
+  }
+  let shoff = align_to(offset, 8);
+  let shnum = sections.len() + 1;
+
+  let mut out: Vec<u8> = Vec::with_capacity(shoff + shnum * SIZEOF_SHDR);
+  let mut ident = [0u8; SIZEOF_IDENT];
+  ident[..4].copy_from_slice(ELFMAG);
+  ident[EI_CLASS] = ELFCLASS64;
+  ident[EI_DATA] = ELFDATA2LSB;
+  ident[EI_VERSION] = EV_CURRENT;
+  out.extend_from_slice(&ident);
+  out.write_u16::<LittleEndian>(ET_EXEC)?;
+  out.write_u16::<LittleEndian>(EM_BPF)?;
+  out.write_u32::<LittleEndian>(EV_CURRENT as u32)?;
+  // Execution starts at the entry trampoline.
+  out.write_u64::<LittleEndian>(0)?;
+  out.write_u64::<LittleEndian>(phoff as u64)?;
+  out.write_u64::<LittleEndian>(shoff as u64)?;
+  out.write_u32::<LittleEndian>(0)?;
+  out.write_u16::<LittleEndian>(SIZEOF_EHDR as u16)?;
+  out.write_u16::<LittleEndian>(SIZEOF_PHDR as u16)?;
+  out.write_u16::<LittleEndian>(2)?;
+  out.write_u16::<LittleEndian>(SIZEOF_SHDR as u16)?;
+  out.write_u16::<LittleEndian>(shnum as u16)?;
+  out.write_u16::<LittleEndian>(shnum as u16 - 1)?;
+
+  // Code segment, then data segment including .bss.
+  for (flags, section_index, memsz) in [
+    (PF_R | PF_X, 0, sections[0].size),
+    (PF_R | PF_W, 1, data_memsz),
+  ] {
+    let section = &sections[section_index];
+    out.write_u32::<LittleEndian>(PT_LOAD)?;
+    out.write_u32::<LittleEndian>(flags)?;
+    out.write_u64::<LittleEndian>(offsets[section_index])?;
+    out.write_u64::<LittleEndian>(section.addr)?;
+    out.write_u64::<LittleEndian>(section.addr)?;
+    out.write_u64::<LittleEndian>(section.data.len() as u64)?;
+    out.write_u64::<LittleEndian>(memsz)?;
+    out.write_u64::<LittleEndian>(section.align)?;
+  }
+
+  for (section, &offset) in sections.iter().zip(offsets.iter()) {
+    out.resize(offset as usize, 0);
+    out.extend_from_slice(&section.data);
+  }
+  out.resize(shoff, 0);
+
+  out.extend_from_slice(&[0u8; SIZEOF_SHDR]);
+  let name_indices = names.into_iter().chain(std::iter::once(shstrtab_name));
+  for ((section, offset), name) in sections.iter().zip(offsets).zip(name_indices) {
+    out.write_u32::<LittleEndian>(name)?;
+    out.write_u32::<LittleEndian>(section.sh_type)?;
+    out.write_u64::<LittleEndian>(section.flags)?;
+    out.write_u64::<LittleEndian>(section.addr)?;
+    out.write_u64::<LittleEndian>(offset)?;
+    out.write_u64::<LittleEndian>(section.size)?;
+    out.write_u32::<LittleEndian>(section.link)?;
+    out.write_u32::<LittleEndian>(section.info)?;
+    out.write_u64::<LittleEndian>(section.align)?;
+    out.write_u64::<LittleEndian>(section.entsize)?;
+  }
+  Ok(out)
+}
+
+/// Builds `.symtab` and `.strtab` with a function symbol for every entry of the offset table.
+/// Entry points are global, other functions local.
+fn symbols(image: &Image, text_index: u16) -> Result<(Section, Section)> {
+  let offset_table = image.offset_table.clone().unwrap_or_default();
+  let mut functions = offset_table
+    .func_offsets
+    .iter()
+    .map(|(name, &offset)| {
+      let global =
+        offset_table.entry_points.is_empty() || offset_table.entry_points.contains_key(name);
+      (global, offset as u64, name.as_str())
+    })
+    .collect::<Vec<_>>();
+  // Local symbols must come first.
+  functions.sort();
+
+  // A function extends to the next function at a higher offset, or to the end of the code.
+  let mut starts = functions.iter().map(|x| x.1).collect::<Vec<_>>();
+  starts.sort_unstable();
+  starts.dedup();
+  let size_of = |offset: u64| {
+    let next = starts.partition_point(|&x| x <= offset);
+    starts.get(next).copied().unwrap_or(image.code.len() as u64) - offset
+  };
+
+  let mut strtab = Strtab::new();
+  let mut symtab = vec![0u8; SIZEOF_SYM];
+  for &(global, offset, name) in &functions {
+    let bind = if global { STB_GLOBAL } else { STB_LOCAL };
+    let name = strtab.add(name);
+    symtab.write_u32::<LittleEndian>(name)?;
+    symtab.write_u8((bind << 4) | STT_FUNC)?;
+    symtab.write_u8(0)?;
+    symtab.write_u16::<LittleEndian>(text_index)?;
+    symtab.write_u64::<LittleEndian>(offset)?;
+    symtab.write_u64::<LittleEndian>(size_of(offset))?;
+  }
+
+  let mut symtab = Section::new(".symtab", SHT_SYMTAB, symtab);
+  // Index of the first global symbol, counting the null symbol.
+  symtab.info = 1 + functions.iter().filter(|x| !x.0).count() as u32;
+  symtab.align = 8;
+  symtab.entsize = SIZEOF_SYM as u64;
+  let strtab = Section::new(".strtab", SHT_STRTAB, strtab.data);
+  Ok((symtab, strtab))
+}
+
+fn align_to(x: usize, align: usize) -> usize {
+  let align = align.max(1);
+  x.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+  use goblin::elf::Elf;
+
+  use super::*;
+  use crate::linker::image::{EntryPoint, HostPlatform, OffsetTable};
+
+  fn image() -> Image {
+    let mut offset_table = OffsetTable::default();
+    for (name, offset) in [("a", 8), ("b", 24), ("c", 32)] {
+      offset_table.func_offsets.insert(name.to_string(), offset);
+    }
+    offset_table.entry_points.insert(
+      "b".to_string(),
+      EntryPoint {
+        offset: 24,
+        arg_count: 1,
+      },
+    );
+    Image {
+      code: (0..40).collect(),
+      data: vec![1, 2, 3, 4, 5],
+      bss_size: 16,
+      platform: Some(HostPlatform {
+        data_offset: 0x100,
+        ..Default::default()
+      }),
+      offset_table: Some(offset_table),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    let image = image();
+    let raw = image_to_elf(&image).unwrap();
+    let elf = Elf::parse(&raw).unwrap();
+    assert_eq!(elf.header.e_machine, EM_BPF);
+    assert_eq!(elf.header.e_type, ET_EXEC);
+
+    let section = |name: &str| {
+      elf
+        .section_headers
+        .iter()
+        .position(|x| elf.shdr_strtab.get_at(x.sh_name) == Some(name))
+        .unwrap()
+    };
+    let text = &elf.section_headers[section(".text")];
+    assert_eq!(
+      &raw[text.sh_offset as usize..(text.sh_offset + text.sh_size) as usize],
+      &image.code[..]
+    );
+    let data = &elf.section_headers[section(".data")];
+    assert_eq!(data.sh_addr, 0x100);
+    let bss = &elf.section_headers[section(".bss")];
+    assert_eq!((bss.sh_addr, bss.sh_size), (0x105, 16));
+
+    let loads = elf
+      .program_headers
+      .iter()
+      .map(|x| (x.p_type, x.p_vaddr, x.p_filesz, x.p_memsz))
+      .collect::<Vec<_>>();
+    assert_eq!(loads, vec![(PT_LOAD, 0, 40, 40), (PT_LOAD, 0x100, 5, 21)]);
+
+    let symtab = &elf.section_headers[section(".symtab")];
+    assert_eq!(symtab.sh_link as usize, section(".strtab"));
+    // The null symbol and the two local functions come before the first global one.
+    assert_eq!(symtab.sh_info, 3);
+    let syms = elf
+      .syms
+      .iter()
+      .skip(1)
+      .map(|x| {
+        (
+          elf.strtab.get_at(x.st_name).unwrap(),
+          x.st_bind(),
+          x.st_value,
+          x.st_size,
+          x.st_shndx,
+        )
+      })
+      .collect::<Vec<_>>();
+    let text_index = section(".text");
+    assert_eq!(
+      syms,
+      vec![
+        ("a", STB_LOCAL, 8, 16, text_index),
+        ("c", STB_LOCAL, 32, 8, text_index),
+        ("b", STB_GLOBAL, 24, 8, text_index),
+      ]
+    );
+  }
+
+  #[test]
+  fn omits_empty_bss() {
+    let image = Image {
+      bss_size: 0,
+      ..image()
+    };
+    let raw = image_to_elf(&image).unwrap();
+    let elf = Elf::parse(&raw).unwrap();
+    assert!(elf
+      .section_headers
+      .iter()
+      .all(|x| elf.shdr_strtab.get_at(x.sh_name) != Some(".bss")));
+    assert_eq!(elf.program_headers[1].p_memsz, 5);
+  }
+}
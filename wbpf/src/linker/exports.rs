@@ -0,0 +1,220 @@
+//! Functions the host may start, and the number of arguments they take.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+  ebpf::{
+    Insn, BPF_ALU, BPF_ALU64, BPF_ALU_OP_MASK, BPF_CALL, BPF_CLS_MASK, BPF_END, BPF_EXIT, BPF_JA,
+    BPF_JMP, BPF_LD, BPF_LDX, BPF_MOV, BPF_ST, BPF_STX, BPF_X, LD_DW_IMM,
+  },
+  local_linker::AnnotatedInsn,
+};
+
+/// Functions in this section are exported.
+pub const ENTRY_SECTION: &str = "wbpf/entry";
+
+const BPF_JMP32: u8 = 0x06;
+
+/// r1-r5.
+const ARG_REGS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Export {
+  pub name: String,
+  /// Overrides the argument count inferred from the code.
+  #[serde(default)]
+  pub arg_count: Option<u32>,
+}
+
+/// Parses an export list with one function per line, optionally followed by its argument count.
+/// `#` starts a comment.
+///
+/// ```text
+/// # entry points
+/// main 2
+/// on_timer
+/// ```
+pub fn parse_export_list(text: &str) -> Result<Vec<Export>> {
+  let mut exports = vec![];
+  for (i, line) in text.lines().enumerate() {
+    let line = line.split('#').next().unwrap().trim();
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+      Some(x) => x,
+      None => continue,
+    };
+    let arg_count = words
+      .next()
+      .map(|x| {
+        x.parse::<u32>()
+          .ok()
+          .filter(|&x| x as usize <= ARG_REGS)
+          .ok_or_else(|| anyhow::anyhow!("line {}: invalid argument count '{}'", i + 1, x))
+      })
+      .transpose()?;
+    if words.next().is_some() {
+      return Err(anyhow::anyhow!("line {}: trailing characters", i + 1));
+    }
+    exports.push(Export {
+      name: name.to_string(),
+      arg_count,
+    });
+  }
+  Ok(exports)
+}
+
+/// Returns the number of argument registers, starting at r1, that `code` may read before writing
+/// them. A call reads the arguments of the called function, as given by `callee_arg_count`, and
+/// none for a helper.
+pub(crate) fn argument_count(
+  code: &[AnnotatedInsn],
+  mut callee_arg_count: impl FnMut((usize, usize)) -> usize,
+) -> usize {
+  // Registers written on every path to each instruction.
+  let mut written: Vec<Option<u16>> = vec![None; code.len()];
+  let mut worklist = vec![];
+  if !code.is_empty() {
+    written[0] = Some(0);
+    worklist.push(0usize);
+  }
+  let mut read_first = 0u16;
+  while let Some(index) = worklist.pop() {
+    let insn = &code[index];
+    let call_args = match insn.call_target_function {
+      Some(target) => callee_arg_count(target),
+      None => 0,
+    };
+    let (reads, writes, successors) = effect(&insn.insn, index, call_args);
+    let state = written[index].unwrap();
+    read_first |= reads & !state;
+    let state = state | writes;
+    for next in successors.into_iter().flatten() {
+      if next >= code.len() {
+        continue;
+      }
+      let joined = written[next].map(|x| x & state).unwrap_or(state);
+      if written[next] != Some(joined) {
+        written[next] = Some(joined);
+        worklist.push(next);
+      }
+    }
+  }
+  (1..=ARG_REGS)
+    .rev()
+    .find(|&r| read_first & (1 << r) != 0)
+    .unwrap_or(0)
+}
+
+/// Registers read and written by `insn`, and the indices of the instructions that may run next.
+fn effect(insn: &Insn, index: usize, call_args: usize) -> (u16, u16, [Option<usize>; 2]) {
+  let reg = |r: u8| 1u16.checked_shl(r as u32).unwrap_or(0);
+  let (dst, src) = (reg(insn.dst), reg(insn.src));
+  let next = Some(index + 1);
+  let op = insn.opc & BPF_ALU_OP_MASK;
+  match insn.opc & BPF_CLS_MASK {
+    BPF_ALU | BPF_ALU64 => {
+      let reads_src = insn.opc & BPF_X != 0 && op != BPF_END;
+      let reads = if op == BPF_MOV { 0 } else { dst } | if reads_src { src } else { 0 };
+      (reads, dst, [next, None])
+    }
+    BPF_LD if insn.opc == LD_DW_IMM => (0, dst, [Some(index + 2), None]),
+    // Legacy packet access, result in r0.
+    BPF_LD => (src, 1, [next, None]),
+    BPF_LDX => (src, dst, [next, None]),
+    BPF_ST => (dst, 0, [next, None]),
+    BPF_STX => (dst | src, 0, [next, None]),
+    BPF_JMP if op == BPF_CALL => {
+      let args = ((1u16 << (call_args + 1)) - 1) & !1;
+      // r0-r5 are clobbered.
+      (args, 0b11_1111, [next, None])
+    }
+    BPF_JMP if op == BPF_EXIT => (1, 0, [None, None]),
+    class @ (BPF_JMP | BPF_JMP32) => {
+      let target = (index as i64 + 1 + insn.off as i64).try_into().ok();
+      if class == BPF_JMP && op == BPF_JA {
+        (0, 0, [target, None])
+      } else {
+        let reads = dst | if insn.opc & BPF_X != 0 { src } else { 0 };
+        (reads, 0, [next, target])
+      }
+    }
+    _ => (0, 0, [next, None]),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::linker::ebpf::{ADD64_REG, CALL, EXIT, JA, JEQ_IMM, MOV64_IMM, MOV64_REG};
+
+  fn insn(opc: u8, dst: u8, src: u8, off: i16, imm: i32) -> AnnotatedInsn {
+    AnnotatedInsn {
+      insn: Insn {
+        opc,
+        dst,
+        src,
+        off,
+        imm,
+      },
+      original_offset: 0,
+      call_target_function: None,
+    }
+  }
+
+  #[test]
+  fn argument_count_joins_branches() {
+    // r2 is only written when r1 == 0.
+    let code = [
+      insn(JEQ_IMM, 1, 0, 2, 0),
+      insn(MOV64_IMM, 3, 0, 0, 1),
+      insn(JA, 0, 0, 1, 0),
+      insn(MOV64_IMM, 2, 0, 0, 5),
+      insn(MOV64_REG, 0, 2, 0, 0),
+      insn(EXIT, 0, 0, 0, 0),
+    ];
+    assert_eq!(argument_count(&code, |_| 0), 2);
+  }
+
+  #[test]
+  fn argument_count_ignores_registers_written_on_all_paths() {
+    let code = [
+      insn(JEQ_IMM, 1, 0, 2, 0),
+      insn(MOV64_IMM, 2, 0, 0, 1),
+      insn(JA, 0, 0, 1, 0),
+      insn(MOV64_IMM, 2, 0, 0, 5),
+      insn(ADD64_REG, 2, 2, 0, 0),
+      insn(MOV64_REG, 0, 2, 0, 0),
+      insn(EXIT, 0, 0, 0, 0),
+    ];
+    assert_eq!(argument_count(&code, |_| 0), 1);
+  }
+
+  #[test]
+  fn argument_count_follows_calls() {
+    let mut code = vec![
+      insn(MOV64_IMM, 1, 0, 0, 1),
+      insn(CALL, 0, 1, 0, -1),
+      insn(EXIT, 0, 0, 0, 0),
+    ];
+    assert_eq!(argument_count(&code, |_| 0), 0);
+    code[1].call_target_function = Some((0, 1));
+    assert_eq!(argument_count(&code, |_| 3), 3);
+    // Arguments of a call do not survive it.
+    code.insert(2, insn(MOV64_REG, 0, 4, 0, 0));
+    assert_eq!(argument_count(&code, |_| 1), 0);
+  }
+
+  #[test]
+  fn export_list() {
+    let exports = parse_export_list("# entry points\nmain 2\n\non_timer # periodic\n").unwrap();
+    let exports = exports
+      .iter()
+      .map(|x| (x.name.as_str(), x.arg_count))
+      .collect::<Vec<_>>();
+    assert_eq!(exports, vec![("main", Some(2)), ("on_timer", None)]);
+    assert!(parse_export_list("main 6").is_err());
+    assert!(parse_export_list("main 1 2").is_err());
+  }
+}
@@ -5,9 +5,17 @@ use bumpalo::Bump;
 
 use crate::linker::global_linker::{GlobalLinker, GlobalLinkerConfig};
 
-use super::image::Image;
+use super::{archive::is_archive, image::Image, map::LinkMap};
 
 pub fn link_files<S: AsRef<Path>>(config: GlobalLinkerConfig, input: &[S]) -> Result<Image> {
+  Ok(link_files_with_map(config, input)?.0)
+}
+
+/// Links `input` and also returns the map of the linked image.
+pub fn link_files_with_map<S: AsRef<Path>>(
+  config: GlobalLinkerConfig,
+  input: &[S],
+) -> Result<(Image, LinkMap)> {
   let input = input
     .iter()
     .map(|x| {
@@ -26,7 +34,12 @@ pub fn link_files<S: AsRef<Path>>(config: GlobalLinkerConfig, input: &[S]) -> Re
   let bump = Bump::new();
   let mut linker = GlobalLinker::new(&bump, config)?;
   for (name, object) in input.iter().zip(files.iter()) {
-    linker.add_object(&name.to_string_lossy(), object)?;
+    if is_archive(object) {
+      linker.add_archive(&name.to_string_lossy(), object)?;
+    } else {
+      linker.add_object(&name.to_string_lossy(), object)?;
+    }
   }
-  Ok(linker.emit()?)
+  let image = linker.emit()?;
+  Ok((image, linker.link_map()))
 }
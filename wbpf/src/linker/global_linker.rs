@@ -1,17 +1,21 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use bumpalo::Bump;
 use fnv::{FnvHashMap, FnvHashSet};
 use goblin::elf64::{
-  section_header::{SHF_ALLOC, SHF_EXECINSTR, SHT_PROGBITS},
-  sym::STB_LOCAL,
+  section_header::{SHF_ALLOC, SHF_EXECINSTR, SHN_UNDEF, SHT_GROUP, SHT_NOBITS, SHT_PROGBITS},
+  sym::{STB_LOCAL, STB_WEAK},
 };
 use petgraph::{
+  algo::tarjan_scc,
   graph::{DiGraph, NodeIndex},
   visit::{Dfs, Visitable},
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
+  dm::DATA_MEMORY_SIZE,
   linker::{
     ebpf::CALL,
     elf_ext::{StrtabExt, SymtabExt},
@@ -20,22 +24,58 @@ use crate::{
 };
 
 use super::{
-  consts::{R_BPF_64_32, R_BPF_64_64},
-  ebpf::{Insn, ADD64_IMM, EXIT, JA, LD_DW_REG, MOV32_IMM},
-  image::{HostPlatform, OffsetTable, TargetMachine},
+  archive::{defined_symbols, parse_archive, undefined_symbols, ArchiveMember},
+  cache::LinkCache,
+  consts::{GRP_COMDAT, R_BPF_64_32, R_BPF_64_64},
+  ebpf::{
+    Insn, ADD64_IMM, EXIT, JA, LD_DW_REG, LSH64_IMM, MOV32_IMM, MOV64_REG, OR64_IMM, ST_DW_REG,
+  },
+  exports::{argument_count, Export, ENTRY_SECTION},
+  image::{
+    DebugInfo, EntryPoint, FunctionDebugInfo, HostPlatform, LineRow, OffsetTable, TargetMachine,
+  },
+  layout::{self, CallCount, CallWeights, FunctionLayout},
+  map::{LinkMap, MapDataSection, MapFunction, MapHelperCall},
 };
 use super::{
   image::Image,
-  local_linker::{LocalLinker, LocalObject},
+  local_linker::{AnnotatedInsn, LocalLinker, LocalObject},
 };
 
+/// Number of instructions in the entry trampoline emitted at offset 0 of every image.
+pub const ENTRY_TRAMPOLINE_INSNS: usize = 13;
+
+/// Number of instructions in a veneer for a call whose target is out of range.
+const VENEER_INSNS: usize = 6;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct GlobalLinkerConfig {
   pub target_machine: TargetMachine,
   pub host_platform: HostPlatform,
   pub dce_roots: Option<Vec<String>>,
+  #[serde(default)]
+  pub layout: FunctionLayout,
+  /// Call counts used by `layout` instead of the number of call sites.
+  #[serde(default)]
+  pub call_profile: Vec<CallCount>,
+  /// Merge functions with identical code and call targets.
+  #[serde(default)]
+  pub identical_code_folding: bool,
+  /// Functions the host may start, in addition to `dce_roots` and the functions in `wbpf/entry`
+  /// sections. If there are none, every global function is exported.
+  #[serde(default)]
+  pub exports: Vec<Export>,
+  /// Leave functions that are not exported out of the offset table.
+  #[serde(default)]
+  pub strip_unexported: bool,
+  /// Directory to cache the analysis of each object in.
+  #[serde(default)]
+  pub cache_dir: Option<PathBuf>,
 }
 
+/// (obj_index, func_index)
+type FunctionId = (usize, usize);
+
 pub struct GlobalLinker<'a> {
   bump: &'a Bump,
   config: GlobalLinkerConfig,
@@ -44,27 +84,53 @@ pub struct GlobalLinker<'a> {
   offset_table: OffsetTable,
   code_image: Vec<u8>,
   data_image: Vec<u8>,
+  bss_size: u32,
   data_section_to_offset: FnvHashMap<(u32, u32), u32>, // (obj_index, section_index) -> offset
+  global_data: FnvIndexMap<String, (usize, u32, bool)>, // name -> (obj_index, offset, weak)
+  discarded_sections: FnvHashSet<(usize, usize)>, // (obj_index, section_index) of duplicate COMDAT groups
+  data_sections: Vec<MapDataSection>,
+  helper_calls: Vec<ResolvedHelperCall>,
+  veneers: FnvHashMap<(FunctionId, FunctionId), usize>, // (caller, target) -> offset
+  folded: FnvIndexMap<String, (FunctionId, FunctionId)>, // name -> (function, folded into)
+  archive_members: Vec<Option<ArchiveMember<'a>>>,      // `None` once loaded
+  exports: FnvIndexMap<String, Option<u32>>,            // name -> argument count override
+  cache: Option<LinkCache>,
+}
+
+struct ResolvedHelperCall {
+  obj_index: usize,
+  func_index: usize,
+  insn_index: usize,
+  helper: String,
+  index: i32,
 }
 
 impl<'a> GlobalLinker<'a> {
   pub fn new(bump: &'a Bump, config: GlobalLinkerConfig) -> Result<Self> {
     Ok(Self {
       bump,
-      config,
       objects: vec![],
       all_functions: Default::default(),
       offset_table: Default::default(),
       code_image: vec![],
       data_image: vec![],
+      bss_size: 0,
       data_section_to_offset: Default::default(),
+      global_data: Default::default(),
+      discarded_sections: Default::default(),
+      data_sections: vec![],
+      helper_calls: vec![],
+      veneers: Default::default(),
+      folded: Default::default(),
+      archive_members: vec![],
+      exports: Default::default(),
+      cache: config.cache_dir.as_ref().map(LinkCache::new),
+      config,
     })
   }
 
   pub fn add_object(&mut self, name: &str, object_file: &[u8]) -> Result<()> {
-    let mut local_linker = LocalLinker::new(Default::default());
-    let obj = local_linker.link(
-      self.bump,
+    let obj = self.link_local(
       self.bump.alloc_str(name),
       self.bump.alloc_slice_copy(object_file),
     )?;
@@ -72,47 +138,507 @@ impl<'a> GlobalLinker<'a> {
     Ok(())
   }
 
+  /// Runs the local linker on an object, or takes its result from the cache.
+  fn link_local(&self, name: &'a str, object_file: &'a [u8]) -> Result<LocalObject<'a>> {
+    if let Some(cache) = &self.cache {
+      match cache.load(self.bump, name, object_file) {
+        Ok(Some(obj)) => {
+          log::debug!("using cached analysis of {}", name);
+          return Ok(obj);
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("failed to read link cache entry for {}: {:?}", name, e),
+      }
+    }
+    let mut local_linker = LocalLinker::new(Default::default());
+    let obj = local_linker.link(self.bump, name, object_file)?;
+    if let Some(cache) = &self.cache {
+      if let Err(e) = cache.store(&obj) {
+        log::warn!("failed to write link cache entry for {}: {:?}", name, e);
+      }
+    }
+    Ok(obj)
+  }
+
+  /// Adds a static archive. A member is linked only if it defines a symbol that is otherwise
+  /// undefined.
+  pub fn add_archive(&mut self, name: &str, archive_file: &[u8]) -> Result<()> {
+    let raw = self.bump.alloc_slice_copy(archive_file);
+    self
+      .archive_members
+      .extend(parse_archive(name, raw)?.into_iter().map(Some));
+    Ok(())
+  }
+
   pub fn emit(&mut self) -> Result<Image> {
+    self.load_archive_members()?;
+    self.select_comdat_groups()?;
     self.emit_data()?;
     self.populate_all_functions()?;
     self.resolve_pseudo_calls()?;
     self.resolve_generic_relocs()?;
+    self.select_exports()?;
 
     if let Some(dce_roots) = self.config.dce_roots.clone() {
-      self.global_dce(&dce_roots)?;
+      let roots = dce_roots
+        .into_iter()
+        .chain(self.exports.keys().cloned())
+        .collect::<Vec<_>>();
+      self.global_dce(&roots)?;
+    }
+    if self.config.identical_code_folding {
+      self.fold_identical_functions();
     }
 
     self.emit_entry_trampoline()?;
     self.emit_code_image()?;
     self.rewrite_image_call_return()?;
     self.emit_offset_table()?;
+    self.emit_stack_depths();
+    self.emit_entry_points()?;
+    let debug_info = self.emit_debug_info();
     let mut image = Image::default();
     image.code = std::mem::replace(&mut self.code_image, vec![]);
     image.data = std::mem::replace(&mut self.data_image, vec![]);
+    image.bss_size = self.bss_size;
     image.machine = Some(self.config.target_machine.clone());
     image.platform = Some(self.config.host_platform.clone());
     image.offset_table = Some(std::mem::replace(
       &mut self.offset_table,
       Default::default(),
     ));
+    image.debug_info = Some(debug_info);
     Ok(image)
   }
 
-  fn emit_offset_table(&mut self) -> Result<()> {
-    let func_offsets = self
+  /// Describes the layout of the image produced by the last call to `emit`.
+  pub fn link_map(&self) -> LinkMap {
+    let kept = self
+      .all_functions
+      .values()
+      .copied()
+      .collect::<FnvHashSet<_>>();
+    let folded_into = |id: FunctionId| self.folded.values().find(|x| x.0 == id).map(|x| x.1);
+    let linked_offset = |obj_index: usize, func_index: usize| {
+      let id = folded_into((obj_index, func_index)).unwrap_or((obj_index, func_index));
+      if kept.contains(&id) {
+        Some(self.objects[id.0].functions[id.1].global_linked_offset as u32)
+      } else {
+        None
+      }
+    };
+    let functions = self
+      .objects
+      .iter()
+      .enumerate()
+      .flat_map(|(obj_index, object)| {
+        object
+          .functions
+          .values()
+          .enumerate()
+          .map(move |(func_index, func)| (obj_index, func_index, object, func))
+      })
+      .map(|(obj_index, func_index, object, func)| MapFunction {
+        name: if func.global {
+          func.name.to_string()
+        } else {
+          format!("{}:{}", object.name, func.name)
+        },
+        object: object.name.to_string(),
+        offset: linked_offset(obj_index, func_index),
+        folded_into: folded_into((obj_index, func_index)).and_then(|id| {
+          self
+            .all_functions
+            .iter()
+            .find(|x| *x.1 == id)
+            .map(|x| x.0.clone())
+        }),
+        size: (func.code.len() * 8) as u32,
+        stack_usage: func.stack_usage as u32,
+        global: func.global,
+        weak: func.weak,
+      })
+      .collect();
+    let helper_calls = self
+      .helper_calls
+      .iter()
+      .map(|call| {
+        let object = &self.objects[call.obj_index];
+        MapHelperCall {
+          object: object.name.to_string(),
+          function: object.functions[call.func_index].name.to_string(),
+          offset: linked_offset(call.obj_index, call.func_index)
+            .map(|x| x + call.insn_index as u32 * 8),
+          helper: call.helper.clone(),
+          index: call.index,
+        }
+      })
+      .collect();
+    LinkMap {
+      functions,
+      data_sections: self.data_sections.clone(),
+      helper_calls,
+    }
+  }
+
+  fn emit_debug_info(&self) -> DebugInfo {
+    let mut files: FnvIndexMap<&str, ()> = FnvIndexMap::default();
+    let functions = self
       .all_functions
       .values()
       .map(|&(obj_index, func_index)| {
         let object = &self.objects[obj_index];
         let func = &object.functions[func_index];
-        (func.name, func.global_linked_offset)
+        FunctionDebugInfo {
+          name: func.name.to_string(),
+          object: object.name.to_string(),
+          offset: func.global_linked_offset as i32,
+          original_offsets: func.code.iter().map(|x| x.original_offset as i32).collect(),
+          lines: func
+            .lines
+            .iter()
+            .map(|x| LineRow {
+              original_offset: x.offset as i32,
+              file: files
+                .insert_full(&object.source_files[x.file as usize], ())
+                .0 as i32,
+              line: x.line as i32,
+              column: x.column as i32,
+            })
+            .collect(),
+        }
       })
-      .collect::<FnvIndexMap<_, _>>();
-    for (k, v) in func_offsets {
+      .collect();
+    DebugInfo {
+      functions,
+      files: files.keys().map(|x| x.to_string()).collect(),
+    }
+  }
+
+  fn emit_offset_table(&mut self) -> Result<()> {
+    for (name, &(obj_index, func_index)) in &self.all_functions {
+      let func = &self.objects[obj_index].functions[func_index];
       self
         .offset_table
         .func_offsets
-        .insert(k.to_string(), v as i32);
+        .insert(name.clone(), func.global_linked_offset as i32);
+    }
+    for (name, &(_, folded_into)) in &self.folded {
+      let target = &self.objects[folded_into.0].functions[folded_into.1];
+      self
+        .offset_table
+        .func_offsets
+        .insert(name.clone(), target.global_linked_offset as i32);
+    }
+    Ok(())
+  }
+
+  fn select_exports(&mut self) -> Result<()> {
+    for export in &self.config.exports {
+      if !self.all_functions.contains_key(&export.name) {
+        return Err(anyhow::anyhow!(
+          "exported function {} is not defined",
+          export.name
+        ));
+      }
+      self.exports.insert(export.name.clone(), export.arg_count);
+    }
+    for root in self.config.dce_roots.iter().flatten() {
+      if self.all_functions.contains_key(root) && !self.exports.contains_key(root) {
+        self.exports.insert(root.clone(), None);
+      }
+    }
+    for (name, &(obj_index, func_index)) in &self.all_functions {
+      let elf = &*self.objects[obj_index].elf;
+      let section_index = self.objects[obj_index].functions[func_index].section_index;
+      let section_name = elf
+        .shdr_strtab
+        .get_at_result(elf.section_headers[section_index].sh_name)?;
+      if section_name == ENTRY_SECTION && !self.exports.contains_key(name) {
+        self.exports.insert(name.clone(), None);
+      }
+    }
+    if self.exports.is_empty() {
+      for (name, &(obj_index, func_index)) in &self.all_functions {
+        if self.objects[obj_index].functions[func_index].global {
+          self.exports.insert(name.clone(), None);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn emit_entry_points(&mut self) -> Result<()> {
+    let mut arg_counts: FnvHashMap<FunctionId, Option<usize>> = FnvHashMap::default();
+    for (name, &arg_count) in &self.exports {
+      let id = match self.all_functions.get(name) {
+        Some(&x) => x,
+        None => {
+          self
+            .folded
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("exported function {} not found", name))?
+            .1
+        }
+      };
+      let inferred = self.argument_count(id, &mut arg_counts);
+      let arg_count = match arg_count {
+        Some(x) => {
+          if (x as usize) < inferred {
+            log::warn!(
+              "exported function {} is declared with {} arguments but reads {}",
+              name,
+              x,
+              inferred
+            );
+          }
+          x as usize
+        }
+        None => inferred,
+      };
+      self.offset_table.entry_points.insert(
+        name.clone(),
+        EntryPoint {
+          offset: self.objects[id.0].functions[id.1].global_linked_offset as i32,
+          arg_count: arg_count as i32,
+        },
+      );
+    }
+
+    if self.config.strip_unexported {
+      let exports = &self.exports;
+      self
+        .offset_table
+        .func_offsets
+        .retain(|name, _| exports.contains_key(name));
+      self
+        .offset_table
+        .max_stack_depths
+        .retain(|name, _| exports.contains_key(name));
+    }
+    Ok(())
+  }
+
+  /// Argument count of `id`, memoized in `memo`. Calls back into a recursive cycle count as
+  /// taking no arguments.
+  fn argument_count(
+    &self,
+    id: FunctionId,
+    memo: &mut FnvHashMap<FunctionId, Option<usize>>,
+  ) -> usize {
+    if let Some(x) = memo.get(&id) {
+      return x.unwrap_or(0);
+    }
+    memo.insert(id, None);
+    let code = &self.objects[id.0].functions[id.1].code;
+    let n = argument_count(code, |callee| self.argument_count(callee, memo));
+    memo.insert(id, Some(n));
+    n
+  }
+
+  /// Merges functions whose code and call targets are identical after relocation, keeping the
+  /// first one in `all_functions`.
+  fn fold_identical_functions(&mut self) {
+    let folds = identical_code_folds(
+      &self
+        .all_functions
+        .values()
+        .map(|&id| (id, &*self.objects[id.0].functions[id.1].code))
+        .collect::<Vec<_>>(),
+    );
+    if folds.is_empty() {
+      return;
+    }
+
+    for (name, id) in std::mem::take(&mut self.all_functions) {
+      match folds.get(&id) {
+        Some(&canonical) => {
+          let object = &self.objects[id.0];
+          log::debug!(
+            "folding function {}:{} into {}:{}",
+            object.name,
+            object.functions[id.1].name,
+            self.objects[canonical.0].name,
+            self.objects[canonical.0].functions[canonical.1].name
+          );
+          self.folded.insert(name, (id, canonical));
+        }
+        None => {
+          self.all_functions.insert(name, id);
+        }
+      }
+    }
+    for &(obj_index, func_index) in self.all_functions.values() {
+      for insn in &mut self.objects[obj_index].functions[func_index].code {
+        if let Some(target) = &mut insn.call_target_function {
+          if let Some(&canonical) = folds.get(target) {
+            *target = canonical;
+          }
+        }
+      }
+    }
+  }
+
+  /// Computes the worst-case stack depth of every function from the call graph.
+  fn emit_stack_depths(&mut self) {
+    let g = self.call_graph();
+    let mut depths: Vec<Option<usize>> = vec![None; self.all_functions.len()];
+    // SCCs come in reverse topological order, so callees are done before their callers.
+    for scc in tarjan_scc(&g) {
+      let recursive = scc.len() > 1 || g.contains_edge(scc[0], scc[0]);
+      for n in scc {
+        let (obj_index, func_index) = self.all_functions[n.index()];
+        let func = &self.objects[obj_index].functions[func_index];
+        depths[n.index()] = if recursive {
+          None
+        } else {
+          // A call pushes an 8-byte return record below the caller's frame. A veneer pushes
+          // another one below that, where the callee's frame starts.
+          let caller = self.all_functions[n.index()];
+          let callees = g
+            .neighbors(n)
+            .map(|x| {
+              let veneer = self
+                .veneers
+                .contains_key(&(caller, self.all_functions[x.index()]));
+              depths[x.index()].map(|d| 8 + if veneer { d.max(8) } else { d })
+            })
+            .collect::<Option<Vec<_>>>();
+          callees
+            .map(|x| func.saved_regs_size + func.stack_usage + x.into_iter().max().unwrap_or(0))
+        };
+      }
+    }
+    for (name, depth) in self.all_functions.keys().zip(depths) {
+      if depth.is_none() {
+        log::warn!(
+          "function {} may recurse, its stack depth is unbounded",
+          name
+        );
+      }
+      self
+        .offset_table
+        .max_stack_depths
+        .insert(name.clone(), depth.map(|x| x as i32).unwrap_or(-1));
+    }
+    for (name, (_, folded_into)) in &self.folded {
+      let depth = self
+        .all_functions
+        .iter()
+        .find(|x| x.1 == folded_into)
+        .and_then(|x| self.offset_table.max_stack_depths.get(x.0))
+        .copied();
+      if let Some(depth) = depth {
+        self
+          .offset_table
+          .max_stack_depths
+          .insert(name.clone(), depth);
+      }
+    }
+  }
+
+  /// Builds a graph with one node per entry of `all_functions` and an edge for every call.
+  fn call_graph(&self) -> DiGraph<(), ()> {
+    let fn_to_index = self
+      .all_functions
+      .values()
+      .enumerate()
+      .map(|(k, v)| (*v, k))
+      .collect::<FnvHashMap<_, _>>();
+    let mut g = DiGraph::<(), ()>::with_capacity(self.all_functions.len(), 0);
+    for _ in 0..self.all_functions.len() {
+      g.add_node(());
+    }
+    for (i, &(obj_index, func_index)) in self.all_functions.values().enumerate() {
+      let object = &self.objects[obj_index];
+      let func = &object.functions[func_index];
+      for insn in func.code.iter() {
+        if let Some(target) = insn.call_target_function {
+          g.update_edge(NodeIndex::new(i), NodeIndex::new(fn_to_index[&target]), ());
+        }
+      }
+    }
+    g
+  }
+
+  fn load_archive_members(&mut self) -> Result<()> {
+    let mut defined: FnvHashSet<&str> = FnvHashSet::default();
+    let mut undefined: Vec<&str> = vec![];
+    for object in &self.objects {
+      defined.extend(defined_symbols(&object.elf)?);
+      undefined.extend(undefined_symbols(&object.elf)?);
+    }
+
+    // The first member defining a symbol wins.
+    let mut providers: FnvHashMap<&str, usize> = FnvHashMap::default();
+    for (i, member) in self.archive_members.iter().enumerate() {
+      for &sym in &member.as_ref().unwrap().defined {
+        providers.entry(sym).or_insert(i);
+      }
+    }
+
+    // Symbols are visited in reference order, including those referenced by loaded members.
+    let mut i = 0;
+    while i < undefined.len() {
+      let sym = undefined[i];
+      i += 1;
+      if defined.contains(sym) {
+        continue;
+      }
+      let member = match providers
+        .get(sym)
+        .and_then(|&x| self.archive_members[x].take())
+      {
+        Some(x) => x,
+        None => continue,
+      };
+      log::debug!("loading archive member {} for {}", member.name, sym);
+      let obj = self.link_local(self.bump.alloc_str(&member.name), member.raw)?;
+      defined.extend(member.defined.iter().copied());
+      undefined.extend(undefined_symbols(&obj.elf)?);
+      self.objects.push(obj);
+    }
+    Ok(())
+  }
+
+  /// Keeps the first copy of each COMDAT group and discards the sections of the others.
+  fn select_comdat_groups(&mut self) -> Result<()> {
+    let mut selected: FnvHashMap<&str, usize> = FnvHashMap::default(); // signature -> obj_index
+    for (obj_idx, object) in self.objects.iter().enumerate() {
+      let elf = &*object.elf;
+      for shdr in &elf.section_headers {
+        if shdr.sh_type != SHT_GROUP {
+          continue;
+        }
+        let file_range = shdr
+          .file_range()
+          .ok_or_else(|| anyhow::anyhow!("missing file range"))?;
+        let words = object
+          .raw
+          .get(file_range)
+          .ok_or_else(|| anyhow::anyhow!("file range out of bounds"))?
+          .chunks_exact(4)
+          .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+          .collect::<Vec<_>>();
+        if words.first().copied().unwrap_or(0) & GRP_COMDAT == 0 {
+          continue;
+        }
+        let signature = elf
+          .shdr_strtab
+          .get_at_result(elf.syms.get_result(shdr.sh_info as usize)?.st_name)?;
+        let kept = *selected.entry(signature).or_insert(obj_idx);
+        if kept != obj_idx {
+          log::debug!(
+            "discarding COMDAT group {} in {}, keeping the one in {}",
+            signature,
+            object.name,
+            self.objects[kept].name
+          );
+          self
+            .discarded_sections
+            .extend(words[1..].iter().map(|&x| (obj_idx, x as usize)));
+        }
+      }
     }
     Ok(())
   }
@@ -120,18 +646,41 @@ impl<'a> GlobalLinker<'a> {
   fn populate_all_functions(&mut self) -> Result<()> {
     for (obj_idx, obj) in self.objects.iter().enumerate() {
       for (func_idx, (func_name, func)) in obj.functions.iter().enumerate() {
+        if self
+          .discarded_sections
+          .contains(&(obj_idx, func.section_index))
+        {
+          continue;
+        }
         let func_name = if func.global {
           func_name.to_string()
         } else {
           format!("{}:{}", obj.name, func_name)
         };
-        if let Some((obj_index, _)) = self.all_functions.get(&func_name) {
-          return Err(anyhow::anyhow!(
-            "multiple definitions of function {} in {} and {}",
-            func_name,
-            self.objects[*obj_index].name,
-            obj.name
-          ));
+        if let Some(&(obj_index, func_index)) = self.all_functions.get(&func_name) {
+          // A non-weak definition overrides weak ones. Otherwise the first weak one is kept.
+          match (
+            self.objects[obj_index].functions[func_index].weak,
+            func.weak,
+          ) {
+            (false, false) => {
+              return Err(anyhow::anyhow!(
+                "multiple definitions of function {} in {} and {}",
+                func_name,
+                self.objects[obj_index].name,
+                obj.name
+              ));
+            }
+            (true, false) => {
+              log::debug!(
+                "weak function {} in {} overridden by {}",
+                func_name,
+                self.objects[obj_index].name,
+                obj.name
+              );
+            }
+            _ => continue,
+          }
         }
         self.all_functions.insert(func_name, (obj_idx, func_idx));
       }
@@ -140,14 +689,17 @@ impl<'a> GlobalLinker<'a> {
   }
 
   fn emit_data(&mut self) -> Result<()> {
+    // Sections are aligned by their address in data memory, not by their offset in the image.
+    let base = self.config.host_platform.data_offset as u64;
     for (obj_idx, object) in self.objects.iter().enumerate() {
       let elf = &*object.elf;
       for (section_index, shdr) in elf.section_headers.iter().enumerate() {
         if shdr.sh_type == SHT_PROGBITS
           && (shdr.sh_flags & SHF_ALLOC as u64) != 0
           && (shdr.sh_flags & SHF_EXECINSTR as u64) == 0
+          && !self.discarded_sections.contains(&(obj_idx, section_index))
         {
-          let data_offset = self.data_image.len();
+          let addr = align_up(base + self.data_image.len() as u64, shdr.sh_addralign)?;
           let file_range = shdr
             .file_range()
             .ok_or_else(|| anyhow::anyhow!("missing file range"))?;
@@ -155,48 +707,167 @@ impl<'a> GlobalLinker<'a> {
             .raw
             .get(file_range)
             .ok_or_else(|| anyhow::anyhow!("file range out of bounds"))?;
+          self.data_image.resize((addr - base) as usize, 0);
           self.data_image.extend_from_slice(data);
-          self.data_section_to_offset.insert(
-            (obj_idx as u32, section_index as u32),
-            data_offset as u32 + self.config.host_platform.data_offset as u32,
-          );
+          self
+            .data_section_to_offset
+            .insert((obj_idx as u32, section_index as u32), addr as u32);
+          self.data_sections.push(MapDataSection {
+            object: object.name.to_string(),
+            section: elf.shdr_strtab.get_at_result(shdr.sh_name)?.to_string(),
+            address: addr as u32,
+            size: data.len() as u32,
+            zero_fill: false,
+          });
         }
       }
     }
+
+    // Zero-initialized sections go after all initialized data and only take space in data memory.
+    let mut end = base + self.data_image.len() as u64;
+    for (obj_idx, object) in self.objects.iter().enumerate() {
+      for (section_index, shdr) in object.elf.section_headers.iter().enumerate() {
+        if shdr.sh_type == SHT_NOBITS
+          && (shdr.sh_flags & SHF_ALLOC as u64) != 0
+          && (shdr.sh_flags & SHF_EXECINSTR as u64) == 0
+          && !self.discarded_sections.contains(&(obj_idx, section_index))
+        {
+          let addr = align_up(end, shdr.sh_addralign)?;
+          end = addr + shdr.sh_size;
+          self
+            .data_section_to_offset
+            .insert((obj_idx as u32, section_index as u32), addr as u32);
+          self.data_sections.push(MapDataSection {
+            object: object.name.to_string(),
+            section: object
+              .elf
+              .shdr_strtab
+              .get_at_result(shdr.sh_name)?
+              .to_string(),
+            address: addr as u32,
+            size: shdr.sh_size as u32,
+            zero_fill: true,
+          });
+        }
+      }
+    }
+    self.bss_size = (end - base - self.data_image.len() as u64) as u32;
+
+    if end > DATA_MEMORY_SIZE as u64 {
+      return Err(anyhow::anyhow!(
+        "data does not fit in data memory: {} bytes of data and {} bytes of zero-initialized data at offset {:#x} exceed {} bytes",
+        self.data_image.len(),
+        self.bss_size,
+        base,
+        DATA_MEMORY_SIZE
+      ));
+    }
+
+    for (obj_idx, object) in self.objects.iter().enumerate() {
+      let elf = &*object.elf;
+      for sym in elf.syms.iter() {
+        if sym.st_bind() == STB_LOCAL || sym.is_function() || sym.st_shndx == SHN_UNDEF as usize {
+          continue;
+        }
+        let section_offset = if let Some(x) = self
+          .data_section_to_offset
+          .get(&(obj_idx as u32, sym.st_shndx as u32))
+        {
+          *x
+        } else {
+          continue;
+        };
+        let sym_name = elf.shdr_strtab.get_at_result(sym.st_name)?;
+        let weak = sym.st_bind() == STB_WEAK;
+        if let Some(&(other_obj_index, _, other_weak)) = self.global_data.get(sym_name) {
+          match (other_weak, weak) {
+            (false, false) => {
+              return Err(anyhow::anyhow!(
+                "multiple definitions of data symbol {} in {} and {}",
+                sym_name,
+                self.objects[other_obj_index].name,
+                object.name
+              ));
+            }
+            (true, false) => {}
+            _ => continue,
+          }
+        }
+        self.global_data.insert(
+          sym_name.to_string(),
+          (obj_idx, section_offset + sym.st_value as u32, weak),
+        );
+      }
+    }
     Ok(())
   }
 
   fn resolve_generic_relocs(&mut self) -> Result<()> {
+    // Functions in discarded COMDAT groups and overridden weak functions are not linked, and
+    // their call relocations were not resolved by `resolve_pseudo_calls`.
+    let linked = self
+      .all_functions
+      .values()
+      .copied()
+      .collect::<FnvHashSet<_>>();
     for (object_index, object) in self.objects.iter_mut().enumerate() {
       let object_reloc = &mut object.reloc;
       let elf = &*object.elf;
       for (&(func_index, offset_in_func), reloc) in &*object_reloc {
+        if !linked.contains(&(object_index, func_index)) {
+          continue;
+        }
         let sym = elf.syms.get_result(reloc.r_sym)?;
         let sym_name = elf.shdr_strtab.get_at_result(sym.st_name)?;
-        if sym.st_bind() != STB_LOCAL {
-          anyhow::bail!(
-            "non-local data relocation is not supported: object {}, symbol {}",
-            object.name,
-            sym_name
-          );
-        }
-        let data_base_offset = *self
-          .data_section_to_offset
-          .get(&(object_index as u32, sym.st_shndx as u32))
-          .ok_or_else(|| anyhow::anyhow!("data offset not found"))?;
-        let this_offset = data_base_offset + sym.st_value as u32;
         let func = &mut object.functions[func_index];
+        // Weak definitions may have been overridden by another object.
+        let this_offset = if sym.st_shndx == SHN_UNDEF as usize || sym.st_bind() == STB_WEAK {
+          self
+            .global_data
+            .get(sym_name)
+            .ok_or_else(|| {
+              anyhow::anyhow!(
+                "undefined {}data symbol {} referenced from {}:{}",
+                if sym.st_bind() == STB_WEAK {
+                  "weak "
+                } else {
+                  ""
+                },
+                sym_name,
+                object.name,
+                func.name
+              )
+            })?
+            .1
+        } else {
+          let data_base_offset = *self
+            .data_section_to_offset
+            .get(&(object_index as u32, sym.st_shndx as u32))
+            .ok_or_else(|| anyhow::anyhow!("data offset not found"))?;
+          data_base_offset + sym.st_value as u32
+        };
+        // Instructions may have been inserted in front of the relocated one by the local linker.
+        let this_insn_index = func
+          .code
+          .iter()
+          .position(|x| x.original_offset == offset_in_func as isize)
+          .ok_or_else(|| {
+            anyhow::anyhow!(
+              "relocation target not found: object {}, func {}, offset {}",
+              object.name,
+              func.name,
+              offset_in_func
+            )
+          })?;
 
         if reloc.r_type == R_BPF_64_64 {
-          let this_insn_index = offset_in_func / 8;
           let next_insn_index = this_insn_index + 1;
-          let value = (func.code[this_insn_index].insn.imm as u64)
-            | ((func.code[next_insn_index].insn.imm as u64) << 32);
+          let value = (func.code[this_insn_index].insn.imm as u32 as u64)
+            | ((func.code[next_insn_index].insn.imm as u32 as u64) << 32);
           let value = value + this_offset as u64;
           func.code[this_insn_index].insn.imm = value as i32;
           func.code[next_insn_index].insn.imm = (value >> 32) as i32;
         } else if reloc.r_type == R_BPF_64_32 {
-          let this_insn_index = offset_in_func / 8;
           let value = func.code[this_insn_index].insn.imm;
           func.code[this_insn_index].insn.imm = value + this_offset as i32;
         } else {
@@ -208,11 +879,11 @@ impl<'a> GlobalLinker<'a> {
           );
         }
         log::debug!(
-          "resolved generic data relocation: object {}, func {}, offset {}, target base {}, symbol {}",
+          "resolved generic data relocation: object {}, func {}, offset {}, target {}, symbol {}",
           object.name,
           func.name,
           offset_in_func,
-          data_base_offset,
+          this_offset,
           sym_name
         );
       }
@@ -221,15 +892,24 @@ impl<'a> GlobalLinker<'a> {
   }
 
   fn resolve_pseudo_calls(&mut self) -> Result<()> {
-    // (obj_index, section_index) -> (offset -> (func_name, func_index))
-    let mut function_map: FnvIndexMap<(usize, usize), FnvIndexMap<usize, (&str, usize)>> =
+    // (obj_index, section_index) -> (offset -> (func_name, target))
+    let mut function_map: FnvIndexMap<(usize, usize), FnvIndexMap<usize, (&str, FunctionId)>> =
       FnvIndexMap::default();
-    for (name, (obj_index, func_index)) in self.all_functions.iter() {
-      let func = &self.objects[*obj_index].functions[*func_index];
-      function_map
-        .entry((*obj_index, func.section_index))
-        .or_default()
-        .insert(func.offset, (name.as_str(), *func_index));
+    for (obj_index, object) in self.objects.iter().enumerate() {
+      for (func_name, func) in object.functions.iter() {
+        // Calls to an overridden weak function go to the definition that replaced it.
+        let name = if func.global {
+          func_name.to_string()
+        } else {
+          format!("{}:{}", object.name, func_name)
+        };
+        if let Some((_, name, target)) = self.all_functions.get_full(&name) {
+          function_map
+            .entry((obj_index, func.section_index))
+            .or_default()
+            .insert(func.offset, (name.as_str(), *target));
+        }
+      }
     }
 
     let objects_snapshot = self.objects.clone();
@@ -238,7 +918,7 @@ impl<'a> GlobalLinker<'a> {
       let elf = &*object.elf;
       let object_reloc = &mut object.reloc;
       let func = &mut object.functions[*func_index];
-      for insn in func.code.iter_mut() {
+      for (insn_index, insn) in func.code.iter_mut().enumerate() {
         if insn.insn.opc == CALL {
           // BPF_PSEUDO_CALL
           if insn.insn.src == 1 {
@@ -276,6 +956,13 @@ impl<'a> GlobalLinker<'a> {
                   {
                     insn.insn.imm = *helper_index;
                     insn.insn.src = 0;
+                    self.helper_calls.push(ResolvedHelperCall {
+                      obj_index: *obj_index,
+                      func_index: *func_index,
+                      insn_index,
+                      helper: sym_name.to_string(),
+                      index: *helper_index,
+                    });
                     log::debug!(
                       "resolved helper call from {}:{} to {}",
                       object.name,
@@ -287,7 +974,12 @@ impl<'a> GlobalLinker<'a> {
                 }
                 if !ok {
                   return Err(anyhow::anyhow!(
-                    "unresolved pseudo call from {}:{} to {}",
+                    "unresolved {}pseudo call from {}:{} to {}",
+                    if sym.st_bind() == STB_WEAK {
+                      "weak "
+                    } else {
+                      ""
+                    },
                     object.name,
                     func.name,
                     sym_name
@@ -299,7 +991,7 @@ impl<'a> GlobalLinker<'a> {
               let target_offset = (func.offset as i32
                 + insn.original_offset as i32
                 + (insn.insn.imm + 1) * 8) as usize;
-              let (name, target_function_index) = *function_map
+              let (name, target) = *function_map
                 .get(&(*obj_index, func.section_index))
                 .unwrap()
                 .get(&target_offset)
@@ -312,7 +1004,7 @@ impl<'a> GlobalLinker<'a> {
                   );
                   anyhow::anyhow!("missing function at target offset")
                 })?;
-              insn.call_target_function = Some((*obj_index, target_function_index));
+              insn.call_target_function = Some(target);
               log::debug!(
                 "resolved local pseudo call from {}:{} to {}",
                 object.name,
@@ -425,6 +1117,7 @@ impl<'a> GlobalLinker<'a> {
         imm: 0,
       },
     ];
+    assert_eq!(insns.len(), ENTRY_TRAMPOLINE_INSNS);
 
     self
       .code_image
@@ -432,11 +1125,85 @@ impl<'a> GlobalLinker<'a> {
     Ok(())
   }
 
+  /// Lays out and emits all functions.
+  ///
+  /// Calls are `JA` instructions with a 16-bit offset. If some call cannot reach its target, the
+  /// functions are reordered so that callees follow their callers, and calls that still cannot
+  /// reach get a veneer placed right after the calling function.
   fn emit_code_image(&mut self) -> Result<()> {
-    for &(obj_index, func_index) in self.all_functions.values() {
-      let object = &mut self.objects[obj_index];
-      let func = &mut object.functions[func_index];
-      func.global_linked_offset = self.code_image.len();
+    let base = self.code_image.len();
+    let mut order = self.function_order(self.config.layout);
+    let mut reordered = self.config.layout != FunctionLayout::Input;
+    // caller -> far targets
+    let mut far_calls: FnvIndexMap<FunctionId, Vec<FunctionId>> = Default::default();
+    loop {
+      self.veneers.clear();
+      let mut offset = base;
+      for &i in &order {
+        let caller = self.all_functions[i];
+        let func = &mut self.objects[caller.0].functions[caller.1];
+        func.global_linked_offset = offset;
+        offset += func.code.len() * 8;
+        for &target in far_calls.get(&caller).into_iter().flatten() {
+          self.veneers.insert((caller, target), offset);
+          offset += VENEER_INSNS * 8;
+        }
+      }
+
+      let mut new_far_calls = vec![];
+      for &caller in self.all_functions.values() {
+        let func = &self.objects[caller.0].functions[caller.1];
+        for (i, insn) in func.code.iter().enumerate() {
+          if let Some(target) = insn.call_target_function {
+            let this_offset = func.global_linked_offset + i * 8;
+            let target_offset = match self.veneers.get(&(caller, target)) {
+              Some(x) => *x,
+              None => self.objects[target.0].functions[target.1].global_linked_offset,
+            };
+            if call_displacement(this_offset, target_offset).is_none()
+              && !new_far_calls.contains(&(caller, target))
+            {
+              new_far_calls.push((caller, target));
+            }
+          }
+        }
+      }
+      if new_far_calls.is_empty() {
+        break;
+      }
+      if !reordered {
+        log::debug!("calls out of range, reordering functions by call graph");
+        order = self.function_order(FunctionLayout::CallGraph);
+        reordered = true;
+        continue;
+      }
+      for (caller, target) in new_far_calls {
+        // Veneers follow the caller, so this only happens if the caller itself is too large.
+        if self.veneers.contains_key(&(caller, target)) {
+          anyhow::bail!(
+            "function {}:{} is too large to reach the veneer for its call to {}:{}",
+            self.objects[caller.0].name,
+            self.objects[caller.0].functions[caller.1].name,
+            self.objects[target.0].name,
+            self.objects[target.0].functions[target.1].name
+          );
+        }
+        log::debug!(
+          "adding veneer for call from {}:{} to {}:{}",
+          self.objects[caller.0].name,
+          self.objects[caller.0].functions[caller.1].name,
+          self.objects[target.0].name,
+          self.objects[target.0].functions[target.1].name
+        );
+        far_calls.entry(caller).or_default().push(target);
+      }
+    }
+
+    for &i in &order {
+      let caller = self.all_functions[i];
+      let object = &self.objects[caller.0];
+      let func = &object.functions[caller.1];
+      assert_eq!(func.global_linked_offset, self.code_image.len());
       log::debug!(
         "emitting function {}:{} at {} len {}",
         object.name,
@@ -448,11 +1215,83 @@ impl<'a> GlobalLinker<'a> {
       for insn in &func.code {
         self.code_image.extend_from_slice(&insn.insn.to_array());
       }
+      for &target in far_calls.get(&caller).into_iter().flatten() {
+        let target_offset = self.objects[target.0].functions[target.1].global_linked_offset;
+        for insn in veneer(target_offset)? {
+          self.code_image.extend_from_slice(&insn.to_array());
+        }
+      }
     }
 
     Ok(())
   }
 
+  /// Returns the order in which to emit the functions in `all_functions`.
+  fn function_order(&self, layout: FunctionLayout) -> Vec<usize> {
+    let weights = self.call_weights();
+    match layout {
+      FunctionLayout::Input => (0..self.all_functions.len()).collect(),
+      FunctionLayout::CallGraph => {
+        let roots = self
+          .config
+          .dce_roots
+          .iter()
+          .flatten()
+          .filter_map(|x| self.all_functions.get_index_of(x))
+          .collect::<Vec<_>>();
+        layout::call_graph_order(self.all_functions.len(), &roots, &weights)
+      }
+      FunctionLayout::PettisHansen => {
+        let sizes = self
+          .all_functions
+          .values()
+          .map(|&(obj_index, func_index)| {
+            self.objects[obj_index].functions[func_index].code.len() * 8
+          })
+          .collect::<Vec<_>>();
+        layout::pettis_hansen_order(&sizes, &weights)
+      }
+    }
+  }
+
+  /// Weights call edges by the configured call profile, or by the number of call sites if there
+  /// is none.
+  fn call_weights(&self) -> CallWeights {
+    let fn_to_index = self
+      .all_functions
+      .values()
+      .enumerate()
+      .map(|(k, v)| (*v, k))
+      .collect::<FnvHashMap<_, _>>();
+    let mut weights = CallWeights::default();
+    for (i, &(obj_index, func_index)) in self.all_functions.values().enumerate() {
+      for insn in &self.objects[obj_index].functions[func_index].code {
+        if let Some(target) = insn.call_target_function {
+          *weights.entry((i, fn_to_index[&target])).or_default() += 1;
+        }
+      }
+    }
+
+    if !self.config.call_profile.is_empty() {
+      weights.values_mut().for_each(|x| *x = 0);
+      for entry in &self.config.call_profile {
+        let edge = self
+          .all_functions
+          .get_index_of(&entry.caller)
+          .zip(self.all_functions.get_index_of(&entry.callee));
+        match edge.and_then(|x| weights.get_mut(&x)) {
+          Some(weight) => *weight += entry.count,
+          None => log::warn!(
+            "ignoring profile entry for unknown call from {} to {}",
+            entry.caller,
+            entry.callee
+          ),
+        }
+      }
+    }
+    weights
+  }
+
   fn rewrite_image_call_return(&mut self) -> Result<()> {
     let func_to_offset = self
       .all_functions
@@ -473,9 +1312,14 @@ impl<'a> GlobalLinker<'a> {
         if let Some(call_target_function) = insn.call_target_function {
           let call_target_function_body =
             &self.objects[call_target_function.0].functions[call_target_function.1];
-          let target_offset = func_to_offset[&call_target_function] as i64;
-          let diff = (target_offset - this_offset) / 8 - 1;
-          let diff = if let Ok(x) = i16::try_from(diff) {
+          let target_offset = match self
+            .veneers
+            .get(&((obj_index, func_index), call_target_function))
+          {
+            Some(x) => *x,
+            None => func_to_offset[&call_target_function],
+          };
+          let diff = if let Some(x) = call_displacement(this_offset as usize, target_offset) {
             x
           } else {
             anyhow::bail!(
@@ -537,24 +1381,7 @@ impl<'a> GlobalLinker<'a> {
       .filter(|x| roots.contains(x.1.as_str()))
       .map(|x| NodeIndex::new(x.0))
       .collect::<Vec<_>>();
-    let fn_to_index = self
-      .all_functions
-      .values()
-      .enumerate()
-      .map(|(k, v)| (*v, k))
-      .collect::<FnvHashMap<_, _>>();
-
-    let mut edges: Vec<(u32, u32)> = Vec::new();
-    for (i, &(obj_index, func_index)) in self.all_functions.values().enumerate() {
-      let object = &self.objects[obj_index];
-      let func = &object.functions[func_index];
-      for insn in func.code.iter() {
-        if let Some(target) = insn.call_target_function {
-          edges.push((i as u32, fn_to_index[&target] as u32));
-        }
-      }
-    }
-    let g = DiGraph::<(), ()>::from_edges(edges.iter().copied());
+    let g = self.call_graph();
     let mut dfs = Dfs::from_parts(root_indices, g.visit_map());
     let mut unused_functions = (0..self.all_functions.len()).collect::<FnvHashSet<_>>();
     while let Some(n) = dfs.next(&g) {
@@ -577,3 +1404,256 @@ impl<'a> GlobalLinker<'a> {
     Ok(())
   }
 }
+
+/// Returns the `JA` offset from the instruction at `from` to `to`, if it fits.
+fn call_displacement(from: usize, to: usize) -> Option<i16> {
+  i16::try_from((to as i64 - from as i64) / 8 - 1).ok()
+}
+
+/// Builds a veneer that jumps to `target`, an absolute code offset.
+///
+/// A call through a veneer has already pushed the return record and moved r10 when the veneer
+/// runs. The veneer pushes a second record holding the unchanged r10 and `target` below it and
+/// returns through that record, which pops it again. This clobbers r0, which is not preserved
+/// across calls, and the 8 bytes below r10, which are part of the callee's stack.
+fn veneer(target: usize) -> Result<Vec<Insn>> {
+  let target = i32::try_from(target)?;
+  let insns = vec![
+    Insn {
+      opc: MOV64_REG,
+      dst: 0,
+      src: 10,
+      off: 0,
+      imm: 0,
+    },
+    Insn {
+      opc: LSH64_IMM,
+      dst: 0,
+      src: 0,
+      off: 0,
+      imm: 32,
+    },
+    Insn {
+      opc: OR64_IMM,
+      dst: 0,
+      src: 0,
+      off: 0,
+      imm: target,
+    },
+    Insn {
+      opc: ST_DW_REG,
+      dst: 10,
+      src: 0,
+      off: -8,
+      imm: 0,
+    },
+    Insn {
+      opc: ADD64_IMM,
+      dst: 10,
+      src: 0,
+      off: 0,
+      imm: -8,
+    },
+    // RETURN
+    Insn {
+      opc: JA,
+      src: 1,
+      dst: 0,
+      off: 0,
+      imm: 0,
+    },
+  ];
+  assert_eq!(insns.len(), VENEER_INSNS);
+  Ok(insns)
+}
+
+/// Rounds `x` up to a multiple of `align`, which is a section alignment from an ELF header.
+fn align_up(x: u64, align: u64) -> Result<u64> {
+  let align = align.max(1);
+  if !align.is_power_of_two() {
+    return Err(anyhow::anyhow!("invalid section alignment {}", align));
+  }
+  Ok((x + align - 1) & !(align - 1))
+}
+
+/// Finds the functions that identical code folding merges, returning each of them with the
+/// function it is folded into. The first of several identical functions is kept. Repeats until no
+/// more functions can be merged, since merging callees can make their callers identical.
+fn identical_code_folds(
+  functions: &[(FunctionId, &[AnnotatedInsn])],
+) -> FnvIndexMap<FunctionId, FunctionId> {
+  type Key = Vec<(u8, u8, u8, i16, i32, Option<FunctionId>)>;
+  let mut folds: FnvIndexMap<FunctionId, FunctionId> = FnvIndexMap::default();
+  loop {
+    let mut seen: FnvHashMap<Key, FunctionId> = FnvHashMap::default();
+    let mut new_folds = vec![];
+    for &(id, code) in functions {
+      if folds.contains_key(&id) {
+        continue;
+      }
+      let key = code
+        .iter()
+        .map(|x| {
+          let insn = &x.insn;
+          match x.call_target_function {
+            // The immediate of a local call is relative to where the caller sits in its section,
+            // so compare calls by the function they resolve to instead.
+            Some(target) => {
+              let target = folds.get(&target).copied().unwrap_or(target);
+              (insn.opc, insn.dst, insn.src, 0, 0, Some(target))
+            }
+            None => (insn.opc, insn.dst, insn.src, insn.off, insn.imm, None),
+          }
+        })
+        .collect::<Key>();
+      match seen.get(&key) {
+        Some(&canonical) => new_folds.push((id, canonical)),
+        None => {
+          seen.insert(key, id);
+        }
+      }
+    }
+    if new_folds.is_empty() {
+      return folds;
+    }
+    for (id, canonical) in new_folds {
+      folds.insert(id, canonical);
+    }
+    // A function kept so far may have been folded into one that comes before it.
+    let snapshot = folds.clone();
+    for canonical in folds.values_mut() {
+      while let Some(&next) = snapshot.get(canonical) {
+        *canonical = next;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::linker::ebpf::{ADD64_IMM, CALL, EXIT, MOV64_IMM};
+
+  fn insn(opc: u8, dst: u8, imm: i32) -> AnnotatedInsn {
+    AnnotatedInsn {
+      insn: Insn {
+        opc,
+        dst,
+        src: 0,
+        off: 0,
+        imm,
+      },
+      original_offset: 0,
+      call_target_function: None,
+    }
+  }
+
+  fn call(target: FunctionId) -> AnnotatedInsn {
+    AnnotatedInsn {
+      call_target_function: Some(target),
+      ..insn(CALL, 0, -1)
+    }
+  }
+
+  fn leaf(value: i32) -> Vec<AnnotatedInsn> {
+    vec![insn(MOV64_IMM, 0, value), insn(EXIT, 0, 0)]
+  }
+
+  fn caller(target: FunctionId) -> Vec<AnnotatedInsn> {
+    vec![call(target), insn(ADD64_IMM, 0, 1), insn(EXIT, 0, 0)]
+  }
+
+  fn folds(functions: &[(FunctionId, Vec<AnnotatedInsn>)]) -> Vec<(FunctionId, FunctionId)> {
+    let functions = functions
+      .iter()
+      .map(|(id, code)| (*id, code.as_slice()))
+      .collect::<Vec<_>>();
+    let mut folds = identical_code_folds(&functions)
+      .into_iter()
+      .collect::<Vec<_>>();
+    folds.sort();
+    folds
+  }
+
+  #[test]
+  fn icf_keeps_distinct_functions() {
+    let functions = [
+      ((0, 0), leaf(1)),
+      ((0, 1), leaf(2)),
+      ((0, 2), caller((0, 0))),
+    ];
+    assert_eq!(folds(&functions), vec![]);
+  }
+
+  #[test]
+  fn icf_folds_callers_of_folded_functions() {
+    // f1 folds into f0 first, which makes g1 identical to g0.
+    let functions = [
+      ((0, 0), caller((0, 2))),
+      ((1, 0), caller((1, 2))),
+      ((0, 2), leaf(1)),
+      ((1, 2), leaf(1)),
+    ];
+    assert_eq!(folds(&functions), vec![((1, 0), (0, 0)), ((1, 2), (0, 2))]);
+  }
+
+  #[test]
+  fn icf_ignores_call_offsets() {
+    // Identical callers at different offsets in their section encode different relative
+    // immediates for the same callee.
+    let mut g0 = caller((0, 2));
+    let mut g1 = caller((0, 2));
+    g0[0].insn.imm = 5;
+    g1[0].insn.imm = -3;
+    let functions = [((0, 0), g0), ((0, 1), g1), ((0, 2), leaf(1))];
+    assert_eq!(folds(&functions), vec![((0, 1), (0, 0))]);
+  }
+
+  #[test]
+  fn icf_refolds_kept_functions() {
+    // d folds into c in the first round. c then folds into e, so d must end up in e too.
+    let e = (0, 0);
+    let c = (0, 1);
+    let d = (0, 2);
+    let a = (0, 3);
+    let b = (0, 4);
+    let functions = [
+      (e, caller(a)),
+      (c, caller(b)),
+      (d, caller(b)),
+      (a, leaf(7)),
+      (b, leaf(7)),
+    ];
+    assert_eq!(folds(&functions), vec![(c, e), (d, e), (b, a)]);
+  }
+
+  #[test]
+  fn icf_keys_folded_local_functions_by_object() {
+    let config = GlobalLinkerConfig {
+      identical_code_folding: true,
+      ..Default::default()
+    };
+    let bump = Bump::new();
+    let mut linker = GlobalLinker::new(&bump, config).unwrap();
+    linker
+      .add_object("icf.o", include_bytes!("../../testdata/icf.o"))
+      .unwrap();
+    let image = linker.emit().unwrap();
+    let func_offsets = image.offset_table.unwrap().func_offsets;
+    assert_eq!(
+      func_offsets["icf.o:double_b"],
+      func_offsets["icf.o:double_a"]
+    );
+    assert!(func_offsets.contains_key("quadruple"));
+    assert!(!func_offsets.contains_key("double_b"));
+  }
+
+  #[test]
+  fn entry_points_reject_unknown_exports() {
+    let bump = Bump::new();
+    let mut linker = GlobalLinker::new(&bump, Default::default()).unwrap();
+    linker.exports.insert("missing".to_string(), None);
+    let err = linker.emit_entry_points().unwrap_err();
+    assert_eq!(err.to_string(), "exported function missing not found");
+  }
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use crate::types::FnvIndexMap;
 
-use super::{ebpf::LD_DW_IMM, image::Image};
+use super::{ebpf::LD_DW_IMM, image::Image, symbolizer::Symbolizer};
 
 pub struct DisassembledImage<'a> {
   image: &'a Image,
@@ -27,6 +27,13 @@ impl<'a> Display for DisassembledImage<'a> {
           .collect::<FnvIndexMap<_, _>>()
       })
       .unwrap_or_default();
+    // Source lines are shown where they change, for images linked with debug info.
+    let symbolizer = self
+      .image
+      .debug_info
+      .as_ref()
+      .map(|_| Symbolizer::new(self.image));
+    let mut last_source = None;
     let mut off = 0usize;
     while off < self.image.code.len() {
       if let Some(func_name) = offset_to_func.get(&off) {
@@ -41,7 +48,18 @@ impl<'a> Display for DisassembledImage<'a> {
         .into_iter()
         .next()
         .unwrap();
-      writeln!(f, "\t{}: {}", off, insn.desc)?;
+      let source = symbolizer
+        .as_ref()
+        .and_then(|x| x.symbolize(off as u32))
+        .and_then(|x| x.source)
+        .map(|x| x.to_string());
+      match &source {
+        Some(x) if source != last_source => writeln!(f, "\t{}: {}\t; {}", off, insn.desc, x)?,
+        _ => writeln!(f, "\t{}: {}", off, insn.desc)?,
+      }
+      if source.is_some() {
+        last_source = source;
+      }
       off += insn_len;
     }
     Ok(())
@@ -0,0 +1,219 @@
+//! Orders functions in the code image to keep callers close to their callees.
+
+use std::{cmp::Reverse, str::FromStr};
+
+use anyhow::Result;
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+
+use crate::types::FnvIndexMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum FunctionLayout {
+  /// Object order, then symbol order within each object.
+  #[default]
+  Input,
+  /// Depth-first from the DCE roots, visiting the most frequently called callees first.
+  CallGraph,
+  /// Pettis-Hansen: repeatedly merge the chains of the two functions connected by the heaviest
+  /// remaining call edge.
+  PettisHansen,
+}
+
+impl FromStr for FunctionLayout {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "input" => Ok(FunctionLayout::Input),
+      "call-graph" => Ok(FunctionLayout::CallGraph),
+      "pettis-hansen" => Ok(FunctionLayout::PettisHansen),
+      _ => Err(anyhow::anyhow!("unknown function layout: {}", s)),
+    }
+  }
+}
+
+/// Number of times `caller` called `callee` in a profiled run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CallCount {
+  pub caller: String,
+  pub callee: String,
+  pub count: u64,
+}
+
+/// Call edges between function indices, in call site order, with their weights.
+pub(crate) type CallWeights = FnvIndexMap<(usize, usize), u64>;
+
+/// Depth-first order starting at `roots`, then at functions nothing calls, then at every other
+/// function, each in input order.
+pub(crate) fn call_graph_order(
+  num_functions: usize,
+  roots: &[usize],
+  weights: &CallWeights,
+) -> Vec<usize> {
+  let mut callees: Vec<Vec<(usize, u64)>> = vec![vec![]; num_functions];
+  for (&(caller, callee), &weight) in weights {
+    callees[caller].push((callee, weight));
+  }
+  for x in &mut callees {
+    // Stable, so equally weighted callees stay in call site order.
+    x.sort_by_key(|callee| Reverse(callee.1));
+  }
+
+  let called = weights
+    .keys()
+    .filter(|x| x.0 != x.1)
+    .map(|x| x.1)
+    .collect::<FnvHashSet<_>>();
+  let uncalled = (0..num_functions).filter(|x| !called.contains(x));
+
+  let mut visited = FnvHashSet::default();
+  let mut order = Vec::with_capacity(num_functions);
+  for start in roots
+    .iter()
+    .copied()
+    .chain(uncalled)
+    .chain(0..num_functions)
+  {
+    let mut stack = vec![start];
+    while let Some(n) = stack.pop() {
+      if !visited.insert(n) {
+        continue;
+      }
+      order.push(n);
+      stack.extend(callees[n].iter().rev().map(|x| x.0));
+    }
+  }
+  order
+}
+
+/// Pettis-Hansen order for functions of the given sizes.
+pub(crate) fn pettis_hansen_order(sizes: &[usize], weights: &CallWeights) -> Vec<usize> {
+  // Calls in both directions count towards the same undirected edge.
+  let mut edges: FnvIndexMap<(usize, usize), u64> = FnvIndexMap::default();
+  for (&(a, b), &weight) in weights {
+    if a != b {
+      *edges.entry((a.min(b), a.max(b))).or_default() += weight;
+    }
+  }
+  let mut edges = edges.into_iter().collect::<Vec<_>>();
+  edges.sort_by_key(|x| Reverse(x.1));
+
+  let mut chains: Vec<Vec<usize>> = (0..sizes.len()).map(|x| vec![x]).collect();
+  let mut chain_of: Vec<usize> = (0..sizes.len()).collect();
+  let mut heat: Vec<u64> = vec![0; sizes.len()];
+  for ((a, b), weight) in edges {
+    let (ca, cb) = (chain_of[a], chain_of[b]);
+    if ca == cb {
+      heat[ca] += weight;
+      continue;
+    }
+    let first = std::mem::take(&mut chains[ca]);
+    let second = std::mem::take(&mut chains[cb]);
+    let merged = closest_merge(first, second, a, b, sizes);
+    for &x in &merged {
+      chain_of[x] = ca;
+    }
+    chains[ca] = merged;
+    heat[ca] += heat[cb] + weight;
+  }
+
+  // Hottest chains first, ties in chain index order.
+  let mut chain_indices = (0..chains.len())
+    .filter(|&x| !chains[x].is_empty())
+    .collect::<Vec<_>>();
+  chain_indices.sort_by_key(|&x| Reverse(heat[x]));
+  chain_indices
+    .into_iter()
+    .flat_map(|x| std::mem::take(&mut chains[x]))
+    .collect()
+}
+
+/// Concatenates two chains, reversing either as needed to minimize the distance between `a` in
+/// `first` and `b` in `second`.
+fn closest_merge(
+  first: Vec<usize>,
+  second: Vec<usize>,
+  a: usize,
+  b: usize,
+  sizes: &[usize],
+) -> Vec<usize> {
+  // Bytes between the start of the chain and the start of `x`.
+  let offset = |chain: &[usize], x: usize| -> usize {
+    chain
+      .iter()
+      .take_while(|&&y| y != x)
+      .map(|&y| sizes[y])
+      .sum()
+  };
+  let len = |chain: &[usize]| -> usize { chain.iter().map(|&y| sizes[y]).sum() };
+  let (first_len, second_len) = (len(&first), len(&second));
+  let a_start = offset(&first, a);
+  let b_start = offset(&second, b);
+  // Distance from the end of `first` back to `a`, and from the start of `second` to `b`, for each
+  // orientation.
+  let a_tail = [first_len - a_start, a_start + sizes[a]];
+  let b_head = [b_start, second_len - b_start - sizes[b]];
+  let (reverse_first, reverse_second) =
+    [(false, false), (true, false), (false, true), (true, true)]
+      .into_iter()
+      .min_by_key(|&(rf, rs)| a_tail[rf as usize] + b_head[rs as usize])
+      .unwrap();
+
+  let mut merged = first;
+  if reverse_first {
+    merged.reverse();
+  }
+  let mut second = second;
+  if reverse_second {
+    second.reverse();
+  }
+  merged.extend(second);
+  merged
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn weights(edges: &[((usize, usize), u64)]) -> CallWeights {
+    edges.iter().copied().collect()
+  }
+
+  #[test]
+  fn closest_merge_reverses_both_chains() {
+    // `a` is at the start of `first` and `b` at the end of `second`.
+    let sizes = [16, 64, 8, 32];
+    let merged = closest_merge(vec![0, 1], vec![2, 3], 0, 3, &sizes);
+    assert_eq!(merged, vec![1, 0, 3, 2]);
+  }
+
+  #[test]
+  fn closest_merge_keeps_adjacent_ends() {
+    let sizes = [16, 64, 8, 32];
+    let merged = closest_merge(vec![0, 1], vec![2, 3], 1, 2, &sizes);
+    assert_eq!(merged, vec![0, 1, 2, 3]);
+  }
+
+  #[test]
+  fn pettis_hansen_orders_hottest_chains_first() {
+    let sizes = [8; 5];
+    let order = pettis_hansen_order(&sizes, &weights(&[((0, 1), 3), ((3, 2), 9)]));
+    assert_eq!(order, vec![2, 3, 0, 1, 4]);
+  }
+
+  #[test]
+  fn pettis_hansen_joins_chains_at_their_callers() {
+    let sizes = [8, 8, 8, 8];
+    let order = pettis_hansen_order(&sizes, &weights(&[((0, 1), 10), ((2, 3), 5), ((2, 1), 1)]));
+    assert_eq!(order, vec![0, 1, 2, 3]);
+  }
+
+  #[test]
+  fn call_graph_order_visits_heaviest_callee_first() {
+    let order = call_graph_order(4, &[0], &weights(&[((0, 1), 1), ((0, 2), 5), ((2, 0), 1)]));
+    assert_eq!(order, vec![0, 2, 1, 3]);
+  }
+}
@@ -0,0 +1,582 @@
+//! Reads the DWARF line table (`.debug_line`, versions 2 to 5) of a relocatable object.
+//!
+//! Addresses in the line program of an object are relative to the section of the code they
+//! describe, which is only known through the relocation on each `DW_LNE_set_address`. Sequences
+//! whose address is not relocated are ignored.
+
+use anyhow::Result;
+use fnv::FnvHashMap;
+use goblin::elf::Elf;
+
+use super::elf_ext::{StrtabExt, SymtabExt};
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+/// A row of the line table of a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+  /// Offset from the start of the function in the object.
+  pub offset: u32,
+  /// Index into the source files of the object.
+  pub file: u32,
+  pub line: u32,
+  pub column: u32,
+}
+
+/// Line table rows of an object, by section index, sorted by address within the section.
+#[derive(Default)]
+pub struct LineTable {
+  pub files: Vec<String>,
+  pub rows: FnvHashMap<usize, Vec<(u64, SourceLine)>>,
+}
+
+impl LineTable {
+  /// Rows for the function at `start..end` in section `section_index`, relative to `start`.
+  pub fn function_rows(&self, section_index: usize, start: u64, end: u64) -> Vec<SourceLine> {
+    let rows = match self.rows.get(&section_index) {
+      Some(x) => x,
+      None => return vec![],
+    };
+    let first = rows.partition_point(|x| x.0 < start);
+    rows[first..]
+      .iter()
+      .take_while(|x| x.0 < end)
+      .map(|(address, row)| SourceLine {
+        offset: (address - start) as u32,
+        ..row.clone()
+      })
+      .collect()
+  }
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+    let x = self
+      .data
+      .get(self.pos..self.pos + n)
+      .ok_or_else(|| anyhow::anyhow!("unexpected end of line table"))?;
+    self.pos += n;
+    Ok(x)
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn uint(&mut self, size: usize) -> Result<u64> {
+    let bytes = self.bytes(size)?;
+    Ok(
+      bytes
+        .iter()
+        .rev()
+        .fold(0u64, |acc, &x| (acc << 8) | x as u64),
+    )
+  }
+
+  fn uleb(&mut self) -> Result<u64> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+      let byte = self.u8()?;
+      if shift < 64 {
+        result |= ((byte & 0x7f) as u64) << shift;
+      }
+      shift += 7;
+      if byte & 0x80 == 0 {
+        return Ok(result);
+      }
+    }
+  }
+
+  fn sleb(&mut self) -> Result<i64> {
+    let mut result = 0i64;
+    let mut shift = 0;
+    loop {
+      let byte = self.u8()?;
+      if shift < 64 {
+        result |= ((byte & 0x7f) as i64) << shift;
+      }
+      shift += 7;
+      if byte & 0x80 == 0 {
+        if shift < 64 && byte & 0x40 != 0 {
+          result |= -1i64 << shift;
+        }
+        return Ok(result);
+      }
+    }
+  }
+
+  fn cstr(&mut self) -> Result<&'a str> {
+    let len = self.data[self.pos..]
+      .iter()
+      .position(|&x| x == 0)
+      .ok_or_else(|| anyhow::anyhow!("unterminated string in line table"))?;
+    let s = std::str::from_utf8(self.bytes(len)?)?;
+    self.pos += 1;
+    Ok(s)
+  }
+}
+
+/// Contents of a section by name, if present.
+fn section_data<'a>(elf: &Elf, raw: &'a [u8], name: &str) -> Result<Option<(usize, &'a [u8])>> {
+  for (index, shdr) in elf.section_headers.iter().enumerate() {
+    if elf.shdr_strtab.get_at_result(shdr.sh_name)? == name {
+      let data = shdr
+        .file_range()
+        .and_then(|x| raw.get(x))
+        .ok_or_else(|| anyhow::anyhow!("section {} out of bounds", name))?;
+      return Ok(Some((index, data)));
+    }
+  }
+  Ok(None)
+}
+
+fn str_at(section: Option<&[u8]>, offset: u64) -> Result<&str> {
+  let data = section
+    .and_then(|x| x.get(offset as usize..))
+    .ok_or_else(|| anyhow::anyhow!("string offset {} out of bounds", offset))?;
+  Reader { data, pos: 0 }.cstr()
+}
+
+/// Sections the line program of an object is read from.
+struct LineSections<'a> {
+  debug_line: &'a [u8],
+  line_str: Option<&'a [u8]>,
+  debug_str: Option<&'a [u8]>,
+  /// Offset in .debug_line -> (section index, value of the symbol plus explicit addend)
+  relocs: FnvHashMap<u64, (usize, u64)>,
+}
+
+pub fn parse_line_table(elf: &Elf, raw: &[u8]) -> Result<LineTable> {
+  let (debug_line_index, debug_line) = match section_data(elf, raw, ".debug_line")? {
+    Some(x) => x,
+    None => return Ok(LineTable::default()),
+  };
+  let line_str = section_data(elf, raw, ".debug_line_str")?.map(|x| x.1);
+  let debug_str = section_data(elf, raw, ".debug_str")?.map(|x| x.1);
+
+  let mut relocs: FnvHashMap<u64, (usize, u64)> = FnvHashMap::default();
+  for (reloc_section_index, section_relocs) in &elf.shdr_relocs {
+    if elf.section_headers[*reloc_section_index].sh_info as usize != debug_line_index {
+      continue;
+    }
+    for reloc in section_relocs.iter() {
+      let sym = elf.syms.get_result(reloc.r_sym)?;
+      relocs.insert(
+        reloc.r_offset,
+        (
+          sym.st_shndx,
+          sym
+            .st_value
+            .wrapping_add(reloc.r_addend.unwrap_or(0) as u64),
+        ),
+      );
+    }
+  }
+
+  parse_line_program(&LineSections {
+    debug_line,
+    line_str,
+    debug_str,
+    relocs,
+  })
+}
+
+fn parse_line_program(sections: &LineSections) -> Result<LineTable> {
+  let (debug_line, line_str, debug_str) =
+    (sections.debug_line, sections.line_str, sections.debug_str);
+  let mut table = LineTable::default();
+  let mut r = Reader {
+    data: debug_line,
+    pos: 0,
+  };
+  while r.pos < debug_line.len() {
+    let mut offset_size = 4;
+    let mut unit_length = r.uint(4)?;
+    if unit_length == 0xffff_ffff {
+      offset_size = 8;
+      unit_length = r.uint(8)?;
+    }
+    let unit_end = r.pos + unit_length as usize;
+    let version = r.uint(2)?;
+    if !(2..=5).contains(&version) {
+      return Err(anyhow::anyhow!(
+        "unsupported line table version {}",
+        version
+      ));
+    }
+    let mut address_size = 8;
+    if version >= 5 {
+      address_size = r.u8()? as usize;
+      let _segment_selector_size = r.u8()?;
+    }
+    let header_length = r.uint(offset_size)?;
+    let program_start = r.pos + header_length as usize;
+    let min_insn_length = r.u8()? as u64;
+    if version >= 4 {
+      let _max_ops_per_insn = r.u8()?;
+    }
+    let _default_is_stmt = r.u8()?;
+    let line_base = r.u8()? as i8 as i64;
+    let line_range = r.u8()? as u64;
+    let opcode_base = r.u8()?;
+    let standard_opcode_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?;
+    if line_range == 0 {
+      return Err(anyhow::anyhow!("line table has zero line range"));
+    }
+
+    // Unit file index -> index in `table.files`
+    let mut files: Vec<Option<u32>> = vec![];
+    let mut dirs: Vec<String> = vec![];
+    if version >= 5 {
+      let read_entries = |r: &mut Reader| -> Result<Vec<(String, u64)>> {
+        let format_count = r.u8()?;
+        let mut format = vec![];
+        for _ in 0..format_count {
+          format.push((r.uleb()?, r.uleb()?));
+        }
+        let count = r.uleb()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+          let mut path = String::new();
+          let mut dir = 0;
+          for &(content_type, form) in &format {
+            let (s, n) = match form {
+              DW_FORM_STRING => (Some(r.cstr()?), 0),
+              DW_FORM_LINE_STRP => (Some(str_at(line_str, r.uint(offset_size)?)?), 0),
+              DW_FORM_STRP => (Some(str_at(debug_str, r.uint(offset_size)?)?), 0),
+              DW_FORM_UDATA => (None, r.uleb()?),
+              DW_FORM_DATA1 => (None, r.uint(1)?),
+              DW_FORM_DATA2 => (None, r.uint(2)?),
+              DW_FORM_DATA4 => (None, r.uint(4)?),
+              DW_FORM_DATA8 => (None, r.uint(8)?),
+              DW_FORM_DATA16 => {
+                r.bytes(16)?;
+                (None, 0)
+              }
+              DW_FORM_BLOCK => {
+                let len = r.uleb()?;
+                r.bytes(len as usize)?;
+                (None, 0)
+              }
+              _ => {
+                return Err(anyhow::anyhow!(
+                  "unsupported form {:#x} in line table",
+                  form
+                ))
+              }
+            };
+            match content_type {
+              DW_LNCT_PATH => path = s.unwrap_or_default().to_string(),
+              DW_LNCT_DIRECTORY_INDEX => dir = n,
+              _ => {}
+            }
+          }
+          entries.push((path, dir));
+        }
+        Ok(entries)
+      };
+      dirs = read_entries(&mut r)?.into_iter().map(|x| x.0).collect();
+      for (name, dir) in read_entries(&mut r)? {
+        add_file(&mut table, &mut files, &dirs, &name, dir);
+      }
+    } else {
+      // Directory and file indices start at 1, with 0 meaning the compilation directory and the
+      // primary source file.
+      dirs.push(String::new());
+      loop {
+        let dir = r.cstr()?;
+        if dir.is_empty() {
+          break;
+        }
+        dirs.push(dir.to_string());
+      }
+      files.push(None);
+      loop {
+        let name = r.cstr()?;
+        if name.is_empty() {
+          break;
+        }
+        let dir = r.uleb()?;
+        let _mtime = r.uleb()?;
+        let _length = r.uleb()?;
+        add_file(&mut table, &mut files, &dirs, name, dir);
+      }
+    }
+
+    r.pos = program_start;
+    let mut section = None;
+    let mut address = 0u64;
+    // The file register starts at 1 in all versions, including 5 where file 0 is valid.
+    let mut file = 1;
+    let mut line = 1i64;
+    let mut column = 0u64;
+    while r.pos < unit_end {
+      let opcode = r.u8()?;
+      if opcode >= opcode_base {
+        let adjusted = (opcode - opcode_base) as u64;
+        address += adjusted / line_range * min_insn_length;
+        line += line_base + (adjusted % line_range) as i64;
+        if let Some(section) = section {
+          push_row(&mut table, &files, section, address, file, line, column);
+        }
+        continue;
+      }
+      match opcode {
+        0 => {
+          let len = r.uleb()? as usize;
+          if len == 0 {
+            continue;
+          }
+          let end = r.pos + len;
+          match r.u8()? {
+            DW_LNE_END_SEQUENCE => {
+              section = None;
+              address = 0;
+              file = 1;
+              line = 1;
+              column = 0;
+            }
+            DW_LNE_SET_ADDRESS => {
+              let operand_offset = r.pos as u64;
+              let value = r.uint(address_size.min(len - 1))?;
+              match sections.relocs.get(&operand_offset) {
+                // REL relocations keep the addend in place.
+                Some(&(shndx, base)) => {
+                  section = Some(shndx);
+                  address = base.wrapping_add(value);
+                }
+                None => {
+                  section = None;
+                  address = value;
+                }
+              }
+            }
+            DW_LNE_DEFINE_FILE => {
+              let name = r.cstr()?;
+              let dir = r.uleb()?;
+              add_file(&mut table, &mut files, &dirs, name, dir);
+            }
+            _ => {}
+          }
+          r.pos = end;
+        }
+        DW_LNS_COPY => {
+          if let Some(section) = section {
+            push_row(&mut table, &files, section, address, file, line, column);
+          }
+        }
+        DW_LNS_ADVANCE_PC => address += r.uleb()? * min_insn_length,
+        DW_LNS_ADVANCE_LINE => line += r.sleb()?,
+        DW_LNS_SET_FILE => file = r.uleb()?,
+        DW_LNS_SET_COLUMN => column = r.uleb()?,
+        DW_LNS_CONST_ADD_PC => address += (255 - opcode_base) as u64 / line_range * min_insn_length,
+        DW_LNS_FIXED_ADVANCE_PC => address += r.uint(2)?,
+        _ => {
+          for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+            r.uleb()?;
+          }
+        }
+      }
+    }
+    r.pos = unit_end;
+  }
+
+  for rows in table.rows.values_mut() {
+    // Stable, so that the last of several rows at one address stays last.
+    rows.sort_by_key(|x| x.0);
+  }
+  Ok(table)
+}
+
+/// Adds the next file of a unit. Files in directory 0, the compilation directory, are named
+/// without their directory.
+fn add_file(
+  table: &mut LineTable,
+  files: &mut Vec<Option<u32>>,
+  dirs: &[String],
+  name: &str,
+  dir: u64,
+) {
+  let path = match dirs.get(dir as usize) {
+    Some(dir_name) if dir != 0 && !name.starts_with('/') => format!("{}/{}", dir_name, name),
+    _ => name.to_string(),
+  };
+  let index = match table.files.iter().position(|x| *x == path) {
+    Some(x) => x,
+    None => {
+      table.files.push(path);
+      table.files.len() - 1
+    }
+  };
+  files.push(Some(index as u32));
+}
+
+fn push_row(
+  table: &mut LineTable,
+  files: &[Option<u32>],
+  section: usize,
+  address: u64,
+  file: u64,
+  line: i64,
+  column: u64,
+) {
+  if let Some(&Some(file)) = files.get(file as usize) {
+    table.rows.entry(section).or_default().push((
+      address,
+      SourceLine {
+        offset: 0,
+        file,
+        line: line as u32,
+        column: column as u32,
+      },
+    ));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECTION: usize = 3;
+  const FUNCTION: u64 = 0x10;
+
+  /// Builds a line table unit with 32-bit offsets, returning it with the offset of its program.
+  fn unit(version: u16, header: &[u8], program: &[u8]) -> (Vec<u8>, usize) {
+    let mut body = version.to_le_bytes().to_vec();
+    if version >= 5 {
+      // Address and segment selector size.
+      body.extend([8, 0]);
+    }
+    body.extend((header.len() as u32).to_le_bytes());
+    body.extend(header);
+    let program_start = 4 + body.len();
+    body.extend(program);
+    let mut unit = (body.len() as u32).to_le_bytes().to_vec();
+    unit.extend(body);
+    (unit, program_start)
+  }
+
+  /// Minimum instruction length, maximum operations per instruction, default `is_stmt`, line
+  /// base -5, line range 14 and opcode base 13 with the standard opcode lengths.
+  fn header_params() -> Vec<u8> {
+    vec![
+      1, 1, 1, -5i8 as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+    ]
+  }
+
+  fn set_address() -> Vec<u8> {
+    let mut x = vec![0, 9, DW_LNE_SET_ADDRESS];
+    x.extend([0; 8]);
+    x
+  }
+
+  /// Advances the address by 8 and the line by 1 and appends a row.
+  const SPECIAL_8_1: u8 = ((1 + 5) + 14 * 8) + 13;
+
+  fn parse(debug_line: Vec<u8>, line_str: &[u8], relocated: &[usize]) -> LineTable {
+    let sections = LineSections {
+      debug_line: &debug_line,
+      line_str: Some(line_str),
+      debug_str: None,
+      relocs: relocated
+        .iter()
+        .map(|&x| (x as u64, (SECTION, FUNCTION)))
+        .collect(),
+    };
+    parse_line_program(&sections).unwrap()
+  }
+
+  fn row(offset: u32, file: u32, line: u32, column: u32) -> SourceLine {
+    SourceLine {
+      offset,
+      file,
+      line,
+      column,
+    }
+  }
+
+  #[test]
+  fn v4() {
+    let mut header = header_params();
+    header.extend(b"inc\0\0");
+    header.extend(b"a.c\0\0\0\0b.h\0\x01\0\0\0");
+    let mut program = set_address();
+    program.extend([DW_LNS_ADVANCE_LINE, 9, DW_LNS_COPY]);
+    program.extend([DW_LNS_SET_COLUMN, 4, SPECIAL_8_1]);
+    program.extend([DW_LNS_SET_FILE, 2, DW_LNS_ADVANCE_PC, 8, DW_LNS_COPY]);
+    program.extend([DW_LNS_ADVANCE_PC, 8, 0, 1, DW_LNE_END_SEQUENCE]);
+    // Sequences without a relocated address are ignored.
+    program.extend(set_address());
+    program.extend([DW_LNS_COPY, 0, 1, DW_LNE_END_SEQUENCE]);
+    let (debug_line, program_start) = unit(4, &header, &program);
+
+    let table = parse(debug_line, &[], &[program_start + 3]);
+    assert_eq!(table.files, vec!["a.c", "inc/b.h"]);
+    assert_eq!(table.rows.len(), 1);
+    assert_eq!(table.rows[&SECTION].len(), 3);
+    assert_eq!(
+      table.function_rows(SECTION, FUNCTION, FUNCTION + 0x18),
+      vec![row(0, 0, 10, 0), row(8, 0, 11, 4), row(0x10, 1, 11, 4)]
+    );
+    assert_eq!(
+      table.function_rows(SECTION, FUNCTION + 8, FUNCTION + 0x10),
+      vec![row(0, 0, 11, 4)]
+    );
+  }
+
+  #[test]
+  fn v5() {
+    let mut header = header_params();
+    // Directories, with paths in .debug_line_str.
+    header.extend([1, DW_LNCT_PATH as u8, DW_FORM_LINE_STRP as u8, 2]);
+    header.extend(0u32.to_le_bytes());
+    header.extend(10u32.to_le_bytes());
+    // Files, with inline paths.
+    header.extend([2, DW_LNCT_PATH as u8, DW_FORM_STRING as u8]);
+    header.extend([DW_LNCT_DIRECTORY_INDEX as u8, DW_FORM_DATA1 as u8, 2]);
+    header.extend(b"a.c\0\0b.h\0\x01");
+    // The file register starts at 1, so the first rows are in b.h.
+    let mut program = set_address();
+    program.extend([DW_LNS_ADVANCE_LINE, 2, DW_LNS_COPY, SPECIAL_8_1]);
+    program.extend([DW_LNS_SET_FILE, 0, DW_LNS_ADVANCE_PC, 8, DW_LNS_COPY]);
+    program.extend([DW_LNS_ADVANCE_PC, 8, 0, 1, DW_LNE_END_SEQUENCE]);
+    let (debug_line, program_start) = unit(5, &header, &program);
+
+    let table = parse(debug_line, b"/src/proj\0inc\0", &[program_start + 3]);
+    assert_eq!(table.files, vec!["a.c", "inc/b.h"]);
+    assert_eq!(
+      table.function_rows(SECTION, FUNCTION, FUNCTION + 0x18),
+      vec![row(0, 1, 3, 0), row(8, 1, 4, 0), row(0x10, 0, 4, 0)]
+    );
+  }
+}
@@ -2,8 +2,10 @@ use std::{collections::BTreeMap, rc::Rc};
 
 use crate::{
   linker::{
-    ebpf::{get_insn, BPF_LD, BPF_LDX, EXIT},
+    ebpf::{get_insn, EXIT, STACK_SIZE},
     elf_ext::{ElfExt, StrtabExt},
+    line_info::{parse_line_table, SourceLine},
+    stack_analysis,
   },
   types::FnvIndexMap,
 };
@@ -11,11 +13,14 @@ use anyhow::Result;
 use bumpalo::Bump;
 use goblin::{
   elf::{Elf, Reloc},
-  elf64::{header::EM_BPF, sym::STB_GLOBAL},
+  elf64::{
+    header::EM_BPF,
+    sym::{STB_GLOBAL, STB_WEAK},
+  },
 };
 use serde::{Deserialize, Serialize};
 
-use super::ebpf::{Insn, ADD64_IMM, BPF_ST, BPF_STX, LD_DW_REG, ST_DW_REG, SUB64_IMM};
+use super::ebpf::{Insn, ADD64_IMM, LD_DW_REG, ST_DW_REG, SUB64_IMM};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct LocalLinkerConfig {}
@@ -31,6 +36,8 @@ pub struct LocalObject<'a> {
   pub raw: &'a [u8],
   pub functions: FnvIndexMap<&'a str, Function<'a>>,
   pub reloc: FnvIndexMap<(usize, usize), Reloc>, // (func_idx, offset) -> reloc
+  /// Source files referenced by `Function::lines`.
+  pub source_files: Vec<String>,
 }
 
 #[derive(Clone, Default)]
@@ -42,8 +49,14 @@ pub struct Function<'a> {
   pub raw_code: Vec<Insn>,
   pub code: Vec<AnnotatedInsn>,
   pub global: bool,
+  /// Can be overridden by a non-weak definition in another object.
+  pub weak: bool,
   pub stack_usage: usize,
+  /// Size of the callee-saved register area below the frame.
+  pub saved_regs_size: usize,
   pub global_linked_offset: usize,
+  /// Line table from the DWARF line info of the object, sorted by offset.
+  pub lines: Vec<SourceLine>,
 }
 
 #[derive(Clone)]
@@ -64,19 +77,9 @@ impl LocalLinker {
     object_name: &'a str,
     object_file: &'a [u8],
   ) -> Result<LocalObject<'a>> {
-    let elf = Elf::parse(object_file)?;
-    if elf.header.e_machine != EM_BPF {
-      return Err(anyhow::anyhow!("not a BPF image: {:?}", elf));
-    }
-
-    let mut obj = LocalObject {
-      name: object_name,
-      elf: Rc::new(elf),
-      raw: object_file,
-      functions: Default::default(),
-      reloc: Default::default(),
-    };
+    let mut obj = LocalObject::parse(object_name, object_file)?;
     obj.populate_functions(bump)?;
+    obj.populate_line_info();
     obj.populate_reloc()?;
     obj.calculate_stack_usage()?;
     obj.patch_callee_saved_regs()?;
@@ -85,6 +88,22 @@ impl LocalLinker {
 }
 
 impl<'a> LocalObject<'a> {
+  /// Parses the object without linking any of its functions.
+  pub(crate) fn parse(name: &'a str, raw: &'a [u8]) -> Result<Self> {
+    let elf = Elf::parse(raw)?;
+    if elf.header.e_machine != EM_BPF {
+      return Err(anyhow::anyhow!("not a BPF image: {:?}", elf));
+    }
+    Ok(Self {
+      name,
+      elf: Rc::new(elf),
+      raw,
+      functions: Default::default(),
+      reloc: Default::default(),
+      source_files: vec![],
+    })
+  }
+
   fn populate_functions(&mut self, _bump: &'a Bump) -> Result<()> {
     let func_syms = self
       .elf
@@ -105,7 +124,8 @@ impl<'a> LocalObject<'a> {
     for &sym in &func_syms {
       let name = self.elf.shdr_strtab.get_at_result(sym.st_name)?;
       let mut func = Function::default();
-      func.global = sym.st_bind() == STB_GLOBAL;
+      func.global = sym.st_bind() == STB_GLOBAL || sym.st_bind() == STB_WEAK;
+      func.weak = sym.st_bind() == STB_WEAK;
       func.name = name;
 
       let shdr = self.elf.get_section_header_result(sym.st_shndx)?;
@@ -148,6 +168,25 @@ impl<'a> LocalObject<'a> {
     }
     Ok(())
   }
+
+  fn populate_line_info(&mut self) {
+    let table = match parse_line_table(&self.elf, self.raw) {
+      Ok(x) => x,
+      Err(e) => {
+        log::warn!("ignoring line info of {}: {:?}", self.name, e);
+        return;
+      }
+    };
+    for func in self.functions.values_mut() {
+      func.lines = table.function_rows(
+        func.section_index,
+        func.offset as u64,
+        func.end_offset as u64,
+      );
+    }
+    self.source_files = table.files;
+  }
+
   fn populate_reloc(&mut self) -> Result<()> {
     // (section_index, start_offset) -> func_index
     let function_lookup_table: BTreeMap<(usize, usize), usize> = self
@@ -162,6 +201,18 @@ impl<'a> LocalObject<'a> {
     for (reloc_section_index, reloc) in &self.elf.shdr_relocs {
       let reloc_section = self.elf.get_section_header_result(*reloc_section_index)?;
       let link_section_index = reloc_section.sh_info;
+      // Relocations in debug info are read by the line table parser.
+      let link_section = self
+        .elf
+        .get_section_header_result(link_section_index as usize)?;
+      if self
+        .elf
+        .shdr_strtab
+        .get_at_result(link_section.sh_name)?
+        .starts_with(".debug_")
+      {
+        continue;
+      }
       for reloc in reloc.iter() {
         let target_function = function_lookup_table
           .range(
@@ -199,31 +250,19 @@ impl<'a> LocalObject<'a> {
 
   fn calculate_stack_usage(&mut self) -> Result<()> {
     for (_, func) in &mut self.functions {
-      let mut stack_usage: usize = 0;
-      for insn in &func.code {
-        let op_class = insn.insn.opc & 0b111;
-        if op_class == BPF_ST || op_class == BPF_STX {
-          if insn.insn.dst == 10 {
-            if insn.insn.off >= 0 {
-              log::warn!("stack offset is non-negative: {}", insn.insn.imm);
-            } else {
-              let offset = (-insn.insn.off) as usize;
-              stack_usage = offset.max(stack_usage);
-            }
-          }
-        } else if op_class == BPF_LD || op_class == BPF_LDX {
-        } else {
-          if insn.insn.src == 10 {
-            log::warn!(
-              "non-trivial use of stack pointer in function {}:{} - assuming max stack size",
-              self.name,
-              func.name,
-            );
-            stack_usage = stack_usage.max(512);
-            break;
-          }
+      let code = func.code.iter().map(|x| x.insn.clone()).collect::<Vec<_>>();
+      let stack_usage = match stack_analysis::stack_usage(&code) {
+        Ok(x) => x,
+        Err(e) => {
+          log::warn!(
+            "unknown use of stack pointer in function {}:{} ({}) - assuming max stack size",
+            self.name,
+            func.name,
+            e
+          );
+          STACK_SIZE
         }
-      }
+      };
       func.stack_usage = stack_usage;
       log::debug!(
         "stack usage for function {}:{}: {}",
@@ -285,6 +324,7 @@ impl<'a> LocalObject<'a> {
       }
 
       // Callee-saved-regs area does not count towards stack usage since it is above the function stack.
+      func.saved_regs_size = count * 8;
       if count != 0 {
         let exit = func.code.pop().unwrap();
         func.code = std::iter::once(AnnotatedInsn {
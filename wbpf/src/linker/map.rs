@@ -0,0 +1,132 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Where functions, data sections and helper calls ended up in a linked image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkMap {
+  pub functions: Vec<MapFunction>,
+  pub data_sections: Vec<MapDataSection>,
+  pub helper_calls: Vec<MapHelperCall>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MapFunction {
+  /// Linked name. Local functions are prefixed with their object.
+  pub name: String,
+  pub object: String,
+  /// Offset in the code image. `None` if the function was removed by dead code elimination.
+  pub offset: Option<u32>,
+  /// Name of the identical function this one was merged into.
+  pub folded_into: Option<String>,
+  /// Size in bytes, including instructions inserted by the linker.
+  pub size: u32,
+  pub stack_usage: u32,
+  pub global: bool,
+  pub weak: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MapDataSection {
+  pub object: String,
+  pub section: String,
+  /// Data memory address.
+  pub address: u32,
+  pub size: u32,
+  /// Whether the section is zero-initialized and not stored in the image.
+  pub zero_fill: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MapHelperCall {
+  pub object: String,
+  pub function: String,
+  /// Offset of the call instruction in the code image. `None` if the calling function was removed.
+  pub offset: Option<u32>,
+  pub helper: String,
+  pub index: i32,
+}
+
+impl Display for LinkMap {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    writeln!(f, "Functions:")?;
+    writeln!(
+      f,
+      "  {:<10} {:>8} {:>6}  {:<6}  {:<24} object",
+      "offset", "size", "stack", "bind", "name"
+    )?;
+    for func in &self.functions {
+      let offset = match func.offset {
+        Some(x) => format!("{:#x}", x),
+        None => "(removed)".to_string(),
+      };
+      writeln!(
+        f,
+        "  {:<10} {:>8} {:>6}  {:<6}  {:<24} {}{}",
+        offset,
+        func.size,
+        func.stack_usage,
+        if func.weak {
+          "weak"
+        } else if func.global {
+          "global"
+        } else {
+          "local"
+        },
+        func.name,
+        func.object,
+        match &func.folded_into {
+          Some(x) => format!(" (folded into {})", x),
+          None => String::new(),
+        }
+      )?;
+    }
+
+    writeln!(f)?;
+    writeln!(f, "Data sections:")?;
+    writeln!(
+      f,
+      "  {:<10} {:>8}  {:<24} object",
+      "address", "size", "section"
+    )?;
+    for section in &self.data_sections {
+      writeln!(
+        f,
+        "  {:<10} {:>8}  {:<24} {}{}",
+        format!("{:#x}", section.address),
+        section.size,
+        section.section,
+        section.object,
+        if section.zero_fill {
+          " (zero-fill)"
+        } else {
+          ""
+        }
+      )?;
+    }
+
+    writeln!(f)?;
+    writeln!(f, "Helper calls:")?;
+    writeln!(
+      f,
+      "  {:<10} {:>8}  {:<24} caller",
+      "offset", "index", "helper"
+    )?;
+    for call in &self.helper_calls {
+      let offset = match call.offset {
+        Some(x) => format!("{:#x}", x),
+        None => "(removed)".to_string(),
+      };
+      writeln!(
+        f,
+        "  {:<10} {:>8}  {:<24} {}:{}",
+        offset, call.index, call.helper, call.object, call.function
+      )?;
+    }
+    Ok(())
+  }
+}
@@ -1,11 +1,20 @@
+pub mod archive;
+pub mod cache;
 pub mod consts;
 pub mod ebpf;
 pub mod ebpf_disassembler;
 pub mod elf_ext;
+pub mod elf_writer;
+pub mod exports;
 pub mod fs;
 pub mod global_linker;
 pub mod image_disassembler;
+pub mod layout;
+pub mod line_info;
 pub mod local_linker;
+pub mod map;
+pub mod stack_analysis;
+pub mod symbolizer;
 
 pub mod image {
   include!(concat!(env!("OUT_DIR"), "/wbpf.linker.image.rs"));
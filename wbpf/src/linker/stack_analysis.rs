@@ -0,0 +1,403 @@
+//! Computes the frame size of a function by tracking pointers derived from r10.
+//!
+//! Every register and every 8-byte stack slot holds an abstract value that is either a scalar or
+//! a pointer into the frame. A frame pointer is described by the lowest offset from r10 it may
+//! point to, and whether that offset is exact. The frame size is the lowest offset that is ever
+//! materialized in a register or accessed through a frame pointer.
+//!
+//! Pointer arithmetic with a scalar register is assumed to stay within the stack object the
+//! pointer points to, so it keeps the lower bound. Anything the analysis cannot follow, like
+//! storing a frame pointer outside of the frame or doing non-additive arithmetic on one, is
+//! reported as an unknown use.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use super::ebpf::{
+  Insn, ADD64_IMM, ADD64_REG, BPF_ALU, BPF_ALU64, BPF_ALU_OP_MASK, BPF_B, BPF_CALL, BPF_CLS_MASK,
+  BPF_DW, BPF_EXIT, BPF_H, BPF_JA, BPF_JMP, BPF_LD, BPF_LDX, BPF_MEM, BPF_MOV, BPF_ST, BPF_STX,
+  BPF_XADD, LD_DW_IMM, MOV64_REG, STACK_SIZE, SUB64_IMM, SUB64_REG,
+};
+
+const BPF_JMP32: u8 = 0x06;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Value {
+  Scalar,
+  /// Pointer to `r10 + min` or above. `exact` if it points exactly to `r10 + min`.
+  Frame {
+    min: i64,
+    exact: bool,
+  },
+}
+
+impl Value {
+  fn join(self, other: Value) -> Value {
+    match (self, other) {
+      (Value::Scalar, Value::Scalar) => Value::Scalar,
+      (Value::Frame { min, .. }, Value::Scalar) | (Value::Scalar, Value::Frame { min, .. }) => {
+        Value::Frame { min, exact: false }
+      }
+      (Value::Frame { min: a, exact: ea }, Value::Frame { min: b, exact: eb }) => Value::Frame {
+        min: a.min(b),
+        exact: ea && eb && a == b,
+      },
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+  regs: [Value; 11],
+  /// Frame pointers spilled to the stack, by offset from r10. Other slots hold scalars.
+  slots: BTreeMap<i64, Value>,
+}
+
+impl State {
+  fn entry() -> Self {
+    let mut regs = [Value::Scalar; 11];
+    regs[10] = Value::Frame {
+      min: 0,
+      exact: true,
+    };
+    Self {
+      regs,
+      slots: BTreeMap::new(),
+    }
+  }
+
+  /// Joins `other` into `self`. Returns whether `self` changed.
+  fn join(&mut self, other: &State) -> bool {
+    let mut changed = false;
+    for (a, b) in self.regs.iter_mut().zip(other.regs.iter()) {
+      let joined = a.join(*b);
+      changed |= joined != *a;
+      *a = joined;
+    }
+    for (offset, value) in &other.slots {
+      let slot = self.slots.entry(*offset).or_insert(Value::Scalar);
+      let joined = slot.join(*value);
+      changed |= joined != *slot;
+      *slot = joined;
+    }
+    for (offset, value) in self.slots.iter_mut() {
+      if !other.slots.contains_key(offset) {
+        let joined = value.join(Value::Scalar);
+        changed |= joined != *value;
+        *value = joined;
+      }
+    }
+    changed
+  }
+}
+
+/// A use of the stack pointer the analysis cannot bound.
+#[derive(Clone, Debug)]
+pub struct UnknownStackUse {
+  /// Index of the offending instruction.
+  pub insn_index: usize,
+  pub reason: &'static str,
+}
+
+impl Display for UnknownStackUse {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{} at insn {}", self.reason, self.insn_index)
+  }
+}
+
+struct Analysis {
+  usage: i64,
+}
+
+impl Analysis {
+  fn touch(&mut self, offset: i64) {
+    self.usage = self.usage.max(-offset);
+  }
+
+  fn record(&mut self, value: Value) {
+    if let Value::Frame { min, .. } = value {
+      self.touch(min);
+    }
+  }
+}
+
+/// Returns the number of bytes below r10 used by `code`.
+pub fn stack_usage(code: &[Insn]) -> Result<usize, UnknownStackUse> {
+  let mut states: Vec<Option<State>> = vec![None; code.len()];
+  let mut worklist = vec![0usize];
+  let mut analysis = Analysis { usage: 0 };
+  if !code.is_empty() {
+    states[0] = Some(State::entry());
+  }
+
+  while let Some(index) = worklist.pop() {
+    let mut state = states[index].clone().unwrap();
+    let successors =
+      step(&code[index], index, &mut state, &mut analysis).map_err(|reason| UnknownStackUse {
+        insn_index: index,
+        reason,
+      })?;
+    if analysis.usage > STACK_SIZE as i64 {
+      return Err(UnknownStackUse {
+        insn_index: index,
+        reason: "frame exceeds maximum stack size",
+      });
+    }
+    for next in successors.into_iter().flatten() {
+      // Running off the end is fine, e.g. after a call to a helper that does not return.
+      if next == code.len() {
+        continue;
+      }
+      if next > code.len() {
+        return Err(UnknownStackUse {
+          insn_index: index,
+          reason: "jump out of function",
+        });
+      }
+      let changed = match &mut states[next] {
+        Some(x) => x.join(&state),
+        x @ None => {
+          *x = Some(state.clone());
+          true
+        }
+      };
+      if changed {
+        worklist.push(next);
+      }
+    }
+  }
+  Ok(analysis.usage.max(0) as usize)
+}
+
+/// Applies `insn` to `state` and returns the indices of the instructions that may run next.
+fn step(
+  insn: &Insn,
+  index: usize,
+  state: &mut State,
+  analysis: &mut Analysis,
+) -> Result<[Option<usize>; 2], &'static str> {
+  let class = insn.opc & BPF_CLS_MASK;
+  let next = Some(index + 1);
+  if insn.dst > 10 || insn.src > 10 {
+    return Err("invalid register");
+  }
+  match class {
+    BPF_ALU | BPF_ALU64 => {
+      let dst = insn.dst as usize;
+      if dst == 10 {
+        return Err("write to r10");
+      }
+      let src = state.regs[insn.src as usize];
+      let value = match insn.opc {
+        MOV64_REG => src,
+        ADD64_IMM | SUB64_IMM => match state.regs[dst] {
+          Value::Frame { min, exact } => Value::Frame {
+            min: if insn.opc == ADD64_IMM {
+              min + insn.imm as i64
+            } else {
+              min - insn.imm as i64
+            },
+            exact,
+          },
+          Value::Scalar => Value::Scalar,
+        },
+        ADD64_REG => match (state.regs[dst], src) {
+          (Value::Scalar, Value::Scalar) => Value::Scalar,
+          (Value::Frame { min, .. }, Value::Scalar) | (Value::Scalar, Value::Frame { min, .. }) => {
+            Value::Frame { min, exact: false }
+          }
+          _ => return Err("sum of two frame pointers"),
+        },
+        SUB64_REG => match (state.regs[dst], src) {
+          (Value::Scalar, Value::Scalar) | (Value::Frame { .. }, Value::Frame { .. }) => {
+            Value::Scalar
+          }
+          _ => return Err("subtraction involving a frame pointer"),
+        },
+        _ if insn.opc & BPF_ALU_OP_MASK == BPF_MOV => {
+          if insn.opc & 0x08 != 0 && src != Value::Scalar {
+            return Err("truncation of a frame pointer");
+          }
+          Value::Scalar
+        }
+        _ => {
+          let uses_src = insn.opc & 0x08 != 0 && insn.opc & BPF_ALU_OP_MASK != 0xd0;
+          if state.regs[dst] != Value::Scalar || (uses_src && src != Value::Scalar) {
+            return Err("arithmetic on a frame pointer");
+          }
+          Value::Scalar
+        }
+      };
+      analysis.record(value);
+      state.regs[dst] = value;
+      Ok([next, None])
+    }
+    BPF_LD => {
+      if insn.opc == LD_DW_IMM {
+        state.regs[insn.dst as usize] = Value::Scalar;
+        Ok([Some(index + 2), None])
+      } else {
+        // Legacy packet access, result in r0.
+        state.regs[0] = Value::Scalar;
+        Ok([next, None])
+      }
+    }
+    BPF_LDX => {
+      let dst = insn.dst as usize;
+      if dst == 10 {
+        return Err("write to r10");
+      }
+      let value = match access(state, analysis, insn.src, insn.off, size_of(insn.opc))? {
+        Some(offset) if insn.opc & 0x18 == BPF_DW => state.slots.get(&offset).copied(),
+        _ => None,
+      };
+      let value = value.unwrap_or(Value::Scalar);
+      analysis.record(value);
+      state.regs[dst] = value;
+      Ok([next, None])
+    }
+    BPF_ST | BPF_STX => {
+      let size = size_of(insn.opc);
+      let value = if class == BPF_STX && insn.opc & 0xe0 == BPF_MEM {
+        state.regs[insn.src as usize]
+      } else if class == BPF_STX && insn.opc & 0xe0 != BPF_XADD {
+        return Err("unsupported store mode");
+      } else {
+        Value::Scalar
+      };
+      let exact_offset = access(state, analysis, insn.dst, insn.off, size)?;
+      match (exact_offset, value) {
+        (Some(offset), _) => {
+          // Clear all slots overlapping the store.
+          let overlapping = state
+            .slots
+            .range(offset - 7..offset + size)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+          for k in overlapping {
+            state.slots.remove(&k);
+          }
+          if let Value::Frame { .. } = value {
+            if size != 8 {
+              return Err("partial store of a frame pointer");
+            }
+            state.slots.insert(offset, value);
+          }
+        }
+        (None, Value::Frame { .. }) => return Err("frame pointer stored outside of the frame"),
+        (None, Value::Scalar) => {}
+      }
+      Ok([next, None])
+    }
+    BPF_JMP | BPF_JMP32 => {
+      let op = insn.opc & BPF_ALU_OP_MASK;
+      if class == BPF_JMP && op == BPF_CALL {
+        // Calls clobber r0-r5. Anything the callee does with pointers passed to it stays within
+        // the objects they point to.
+        for reg in &mut state.regs[0..6] {
+          *reg = Value::Scalar;
+        }
+        Ok([next, None])
+      } else if class == BPF_JMP && op == BPF_EXIT {
+        Ok([None, None])
+      } else {
+        let target = index as i64 + 1 + insn.off as i64;
+        if target < 0 {
+          return Err("jump out of function");
+        }
+        let target = Some(target as usize);
+        if class == BPF_JMP && op == BPF_JA {
+          Ok([target, None])
+        } else {
+          Ok([next, target])
+        }
+      }
+    }
+    _ => Err("unknown instruction class"),
+  }
+}
+
+/// Records a memory access through `base + off` and returns the exact frame offset accessed, if
+/// known.
+fn access(
+  state: &State,
+  analysis: &mut Analysis,
+  base: u8,
+  off: i16,
+  size: i64,
+) -> Result<Option<i64>, &'static str> {
+  match state.regs[base as usize] {
+    Value::Scalar => Ok(None),
+    Value::Frame { min, exact } => {
+      let offset = min + off as i64;
+      if exact && offset + size > 0 {
+        log::warn!("stack access at non-negative offset {}", offset);
+      }
+      analysis.touch(offset);
+      Ok(if exact { Some(offset) } else { None })
+    }
+  }
+}
+
+fn size_of(opc: u8) -> i64 {
+  match opc & 0x18 {
+    BPF_B => 1,
+    BPF_H => 2,
+    BPF_DW => 8,
+    _ => 4,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::linker::ebpf::{EXIT, JNE_REG, LD_DW_REG, MOV64_IMM, ST_B_IMM, ST_DW_REG};
+
+  fn insn(opc: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+      opc,
+      dst,
+      src,
+      off,
+      imm,
+    }
+  }
+
+  #[test]
+  fn direct_store() {
+    let code = [insn(ST_DW_REG, 10, 1, -16, 0), insn(EXIT, 0, 0, 0, 0)];
+    assert_eq!(stack_usage(&code).unwrap(), 16);
+  }
+
+  #[test]
+  fn spilled_pointer() {
+    let code = [
+      insn(MOV64_REG, 1, 10, 0, 0),
+      insn(ADD64_IMM, 1, 0, 0, -32),
+      insn(ST_DW_REG, 10, 1, -8, 0),
+      insn(MOV64_IMM, 1, 0, 0, 0),
+      insn(LD_DW_REG, 2, 10, -8, 0),
+      insn(ST_B_IMM, 2, 0, 0, 0),
+      insn(EXIT, 0, 0, 0, 0),
+    ];
+    assert_eq!(stack_usage(&code).unwrap(), 32);
+  }
+
+  #[test]
+  fn pointer_walking_down_in_loop() {
+    let code = [
+      insn(MOV64_REG, 1, 10, 0, 0),
+      insn(ADD64_IMM, 1, 0, 0, -8),
+      insn(ST_DW_REG, 1, 0, 0, 0),
+      insn(JNE_REG, 1, 0, -3, 0),
+      insn(EXIT, 0, 0, 0, 0),
+    ];
+    let err = stack_usage(&code).unwrap_err();
+    assert_eq!(err.reason, "frame exceeds maximum stack size");
+  }
+
+  #[test]
+  fn no_stack_use() {
+    let code = [insn(MOV64_IMM, 0, 0, 0, 1), insn(EXIT, 0, 0, 0, 0)];
+    assert_eq!(stack_usage(&code).unwrap(), 0);
+  }
+}
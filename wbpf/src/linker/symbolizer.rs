@@ -0,0 +1,251 @@
+use std::fmt::Display;
+
+use super::{
+  ebpf::LD_DW_IMM,
+  image::{Image, LineRow},
+};
+
+/// Number of instructions shown on each side of the faulting instruction.
+const CONTEXT_INSNS: usize = 4;
+
+/// Maps code offsets in a linked image back to the function and source object they came from.
+pub struct Symbolizer<'a> {
+  image: &'a Image,
+  functions: Vec<FunctionRange<'a>>,
+  files: &'a [String],
+}
+
+struct FunctionRange<'a> {
+  name: &'a str,
+  object: Option<&'a str>,
+  offset: u32,
+  original_offsets: &'a [i32],
+  lines: &'a [LineRow],
+}
+
+/// The location of a code offset inside a linked function.
+#[derive(Clone, Debug)]
+pub struct Symbol<'a> {
+  pub function: &'a str,
+  /// Source object of the function. Unknown for images linked without debug info.
+  pub object: Option<&'a str>,
+  /// Offset of the function in the image.
+  pub function_offset: u32,
+  /// Offset from the start of the linked function.
+  pub offset: u32,
+  /// Offset from the start of the function in the source object, if the instruction came from it.
+  pub original_offset: Option<u32>,
+  /// Source location from the line info of the object, if any.
+  pub source: Option<SourceLocation<'a>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceLocation<'a> {
+  pub file: &'a str,
+  pub line: u32,
+  /// Zero if unknown.
+  pub column: u32,
+}
+
+impl<'a> Symbolizer<'a> {
+  pub fn new(image: &'a Image) -> Self {
+    let mut functions: Vec<FunctionRange> = match &image.debug_info {
+      Some(debug_info) => debug_info
+        .functions
+        .iter()
+        .map(|x| FunctionRange {
+          name: x.name.as_str(),
+          object: Some(x.object.as_str()),
+          offset: x.offset as u32,
+          original_offsets: &x.original_offsets,
+          lines: &x.lines,
+        })
+        .collect(),
+      None => image
+        .offset_table
+        .iter()
+        .flat_map(|x| x.func_offsets.iter())
+        .map(|(name, offset)| FunctionRange {
+          name: name.as_str(),
+          object: None,
+          offset: *offset as u32,
+          original_offsets: &[],
+          lines: &[],
+        })
+        .collect(),
+    };
+    functions.sort_by_key(|x| x.offset);
+    let files = image
+      .debug_info
+      .as_ref()
+      .map(|x| x.files.as_slice())
+      .unwrap_or_default();
+    Self {
+      image,
+      functions,
+      files,
+    }
+  }
+
+  fn function_index(&self, pc: u32) -> Option<usize> {
+    if pc as usize >= self.image.code.len() {
+      return None;
+    }
+    self
+      .functions
+      .partition_point(|x| x.offset <= pc)
+      .checked_sub(1)
+  }
+
+  /// Resolves a code offset to a symbol. Returns `None` for offsets in the entry trampoline or
+  /// outside of the image.
+  pub fn symbolize(&self, pc: u32) -> Option<Symbol<'a>> {
+    let func = &self.functions[self.function_index(pc)?];
+    let offset = pc - func.offset;
+    let original_offset = func
+      .original_offsets
+      .get(offset as usize / 8)
+      .copied()
+      .filter(|x| *x >= 0)
+      .map(|x| x as u32);
+    let source = original_offset.and_then(|x| self.source_location(func, x));
+    Some(Symbol {
+      function: func.name,
+      object: func.object,
+      function_offset: func.offset,
+      offset,
+      original_offset,
+      source,
+    })
+  }
+
+  /// Finds the line table row covering `original_offset`.
+  fn source_location(
+    &self,
+    func: &FunctionRange,
+    original_offset: u32,
+  ) -> Option<SourceLocation<'a>> {
+    let index = func
+      .lines
+      .partition_point(|x| x.original_offset as u32 <= original_offset)
+      .checked_sub(1)?;
+    let row = &func.lines[index];
+    Some(SourceLocation {
+      file: self.files.get(row.file as usize)?.as_str(),
+      line: row.line as u32,
+      column: row.column as u32,
+    })
+  }
+
+  /// Returns a printable report of the location of `pc` and the instructions surrounding it.
+  pub fn report(&self, pc: u32) -> FaultReport<'_, 'a> {
+    FaultReport {
+      symbolizer: self,
+      pc,
+    }
+  }
+
+  /// Decodes the instruction offsets in `start..end`, keeping wide instructions intact.
+  fn insn_offsets(&self, start: u32, end: u32) -> Vec<(u32, usize)> {
+    let code = &self.image.code;
+    let end = (end as usize).min(code.len());
+    let mut off = start as usize;
+    let mut offsets = vec![];
+    while off + 8 <= end {
+      let insn_len = if code[off] == LD_DW_IMM && off + 16 <= end {
+        16usize
+      } else {
+        8usize
+      };
+      offsets.push((off as u32, insn_len));
+      off += insn_len;
+    }
+    offsets
+  }
+}
+
+pub struct FaultReport<'s, 'a> {
+  symbolizer: &'s Symbolizer<'a>,
+  pc: u32,
+}
+
+impl<'s, 'a> Display for FaultReport<'s, 'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let symbolizer = self.symbolizer;
+    let code = &symbolizer.image.code;
+    let (start, end) = match symbolizer.function_index(self.pc) {
+      Some(i) => {
+        writeln!(f, "at {}", symbolizer.symbolize(self.pc).unwrap())?;
+        let end = symbolizer
+          .functions
+          .get(i + 1)
+          .map(|x| x.offset)
+          .unwrap_or(code.len() as u32);
+        (symbolizer.functions[i].offset, end)
+      }
+      None if (self.pc as usize) < code.len() => {
+        writeln!(f, "at <entry trampoline>+{:#x}", self.pc)?;
+        let end = symbolizer
+          .functions
+          .first()
+          .map(|x| x.offset)
+          .unwrap_or(code.len() as u32);
+        (0, end)
+      }
+      None => return writeln!(f, "at {:#x} (outside of image)", self.pc),
+    };
+
+    let offsets = symbolizer.insn_offsets(start, end);
+    if offsets.is_empty() {
+      return Ok(());
+    }
+    let index = offsets
+      .iter()
+      .position(|&(off, len)| self.pc >= off && self.pc < off + len as u32)
+      .unwrap_or(0);
+    let window =
+      &offsets[index.saturating_sub(CONTEXT_INSNS)..(index + CONTEXT_INSNS + 1).min(offsets.len())];
+    for &(off, len) in window {
+      let insn = super::ebpf_disassembler::to_insn_vec(&code[off as usize..off as usize + len])
+        .into_iter()
+        .next()
+        .unwrap();
+      let marker = if off == offsets[index].0 { "=>" } else { "  " };
+      writeln!(f, "  {} {}: {}", marker, off, insn.desc)?;
+    }
+    Ok(())
+  }
+}
+
+impl<'a> Display for Symbol<'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let object = match self.object {
+      Some(x) => x,
+      None => return write!(f, "{}+{:#x}", self.function, self.offset),
+    };
+    match self.original_offset {
+      Some(original_offset) => write!(f, "{}:{}+{:#x}", object, self.function, original_offset)?,
+      None => {
+        return write!(
+          f,
+          "{}:{}+{:#x} (inserted by linker)",
+          object, self.function, self.offset
+        )
+      }
+    }
+    match &self.source {
+      Some(source) => write!(f, " ({})", source),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<'a> Display for SourceLocation<'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}:{}", self.file, self.line)?;
+    if self.column != 0 {
+      write!(f, ":{}", self.column)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,395 @@
+//! Runs batches of jobs across all PEs of a device.
+//!
+//! Every PE gets a worker that pulls jobs from a shared queue, preferring jobs whose image is
+//! already resident in its code memory. Two resources are shared between PEs and are arbitrated
+//! here:
+//!
+//! - The entry state area at the start of data memory. Launches are serialized and a launch is
+//!   only considered done once the entry trampoline has consumed the state.
+//! - The data image. Programs may write to their globals, so jobs whose images carry data run
+//!   one at a time, each starting from a freshly loaded copy of its data.
+//!
+//! Buffers and stacks are allocated from a per-PE share of the data memory after the largest data
+//! image in the batch, so they never overlap the data of a job running on another PE.
+//!
+//! The scheduler assumes it is the only user of the device while a batch is running.
+
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+use anyhow::Result;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+use crate::{
+  backend::Backend,
+  device::{Device, Launch, MachineState, RunOptions, RunResult},
+  dm::DATA_MEMORY_SIZE,
+  dm_alloc::DmAllocator,
+  helper::HelperRegistry,
+  linker::{global_linker::ENTRY_TRAMPOLINE_INSNS, image::Image},
+};
+
+pub struct Job {
+  pub image: Arc<Image>,
+  pub state: MachineState,
+  pub options: RunOptions,
+}
+
+pub struct JobResult {
+  /// Index of the job in the batch passed to `Scheduler::run_batch`.
+  pub job_index: usize,
+  pub pe_index: u32,
+  pub result: Result<RunResult>,
+}
+
+type HelperFactory<B> = Box<dyn Fn(u32) -> HelperRegistry<B> + Send + Sync>;
+
+pub struct Scheduler<B: Backend> {
+  inner: Arc<SchedulerInner<B>>,
+}
+
+impl<B: Backend> Clone for Scheduler<B> {
+  fn clone(&self) -> Self {
+    Self {
+      inner: self.inner.clone(),
+    }
+  }
+}
+
+struct SchedulerInner<B: Backend> {
+  device: Device<B>,
+  make_helpers: HelperFactory<B>,
+  pes: Vec<AsyncMutex<PeSlot>>,
+  launch_lock: AsyncMutex<()>,
+  /// Held by the job whose data image is in data memory.
+  data_lock: AsyncMutex<()>,
+}
+
+#[derive(Default)]
+struct PeSlot {
+  /// Image whose code is loaded on this PE.
+  resident: Option<Arc<Image>>,
+}
+
+type JobQueue = Mutex<VecDeque<(usize, Job)>>;
+
+impl<B: Backend> Scheduler<B> {
+  pub fn new(device: Device<B>) -> Self {
+    Self::with_helpers(device, |_| HelperRegistry::new())
+  }
+
+  /// Creates a scheduler whose workers service helper calls with registries built by
+  /// `make_helpers`, which is called once per PE and batch.
+  pub fn with_helpers(
+    device: Device<B>,
+    make_helpers: impl Fn(u32) -> HelperRegistry<B> + Send + Sync + 'static,
+  ) -> Self {
+    let pes = (0..device.num_pe())
+      .map(|_| AsyncMutex::new(PeSlot::default()))
+      .collect();
+    Self {
+      inner: Arc::new(SchedulerInner {
+        device,
+        make_helpers: Box::new(make_helpers),
+        pes,
+        launch_lock: AsyncMutex::new(()),
+        data_lock: AsyncMutex::new(()),
+      }),
+    }
+  }
+
+  pub fn device(&self) -> &Device<B> {
+    &self.inner.device
+  }
+
+  /// Spawns one worker per PE on the current tokio runtime and returns a channel yielding each
+  /// job's result as it completes. The channel closes once all jobs are done.
+  pub fn run_batch(&self, jobs: Vec<Job>) -> Result<mpsc::UnboundedReceiver<JobResult>> {
+    if self.inner.pes.is_empty() {
+      anyhow::bail!("device has no PEs to run jobs on");
+    }
+    // Each PE allocates buffers and stacks from its own share of the memory left free by every
+    // image in the batch.
+    let mut alloc = DmAllocator::new(0..DATA_MEMORY_SIZE as u32);
+    for job in &jobs {
+      let x = DmAllocator::for_image(&job.image)?;
+      if x.remaining() < alloc.remaining() {
+        alloc = x;
+      }
+    }
+    let allocs = alloc.split(self.inner.pes.len() as u32);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let queue: Arc<JobQueue> = Arc::new(Mutex::new(jobs.into_iter().enumerate().collect()));
+    for (pe_index, alloc) in allocs.into_iter().enumerate() {
+      let me = self.clone();
+      let queue = queue.clone();
+      let tx = tx.clone();
+      tokio::spawn(async move { me.worker(pe_index as u32, alloc, queue, tx).await });
+    }
+    Ok(rx)
+  }
+
+  async fn worker(
+    &self,
+    pe_index: u32,
+    alloc: DmAllocator,
+    queue: Arc<JobQueue>,
+    tx: mpsc::UnboundedSender<JobResult>,
+  ) {
+    let mut slot = self.inner.pes[pe_index as usize].lock().await;
+    let mut helpers = (self.inner.make_helpers)(pe_index);
+    while let Some((job_index, job)) = Self::next_job(&queue, slot.resident.as_deref()) {
+      let result = self
+        .run_job(pe_index, &mut slot, &job, alloc.clone(), &mut helpers)
+        .await;
+      if let Err(e) = &result {
+        log::warn!("job {} failed on PE {}: {:?}", job_index, pe_index, e);
+      }
+      let _ = tx.send(JobResult {
+        job_index,
+        pe_index,
+        result,
+      });
+    }
+  }
+
+  /// Takes the first queued job whose code is already resident, or the first job otherwise.
+  fn next_job(queue: &JobQueue, resident: Option<&Image>) -> Option<(usize, Job)> {
+    let mut queue = queue.lock().unwrap();
+    let index = resident
+      .and_then(|resident| {
+        queue
+          .iter()
+          .position(|(_, job)| job.image.code == resident.code)
+      })
+      .unwrap_or(0);
+    queue.remove(index)
+  }
+
+  async fn run_job(
+    &self,
+    pe_index: u32,
+    slot: &mut PeSlot,
+    job: &Job,
+    mut alloc: DmAllocator,
+    helpers: &mut HelperRegistry<B>,
+  ) -> Result<RunResult> {
+    let device = &self.inner.device;
+    let deadline = job.options.timeout.map(|x| Instant::now() + x);
+    device.stop(pe_index)?;
+    device.wait_for_stop(pe_index, deadline).await?;
+
+    if slot.resident.as_ref().map(|x| &x.code) != Some(&job.image.code) {
+      slot.resident = None;
+      device.load_code(pe_index, 0, &job.image.code)?;
+      slot.resident = Some(job.image.clone());
+    } else {
+      log::debug!("code already resident on PE {}", pe_index);
+    }
+
+    let _data_guard = if job.image.data.is_empty() && job.image.bss_size == 0 {
+      None
+    } else {
+      let guard = self.inner.data_lock.lock().await;
+      device.load_data(&job.image).await?;
+      Some(guard)
+    };
+    let resolved = job
+      .state
+      .resolve(&device.data_memory().await?, &job.image, &mut alloc)?;
+    resolved.check_stack(&job.image, &job.state.entry_point)?;
+    let launch = {
+      let _guard = self.inner.launch_lock.lock().await;
+      let launch = device
+        .launch(
+          &job.image,
+          &job.state.entry_point,
+          &resolved.registers,
+          pe_index,
+        )
+        .await?;
+      self
+        .wait_for_trampoline(pe_index, &launch, deadline)
+        .await?;
+      launch
+    };
+    device
+      .supervise(
+        &job.image,
+        pe_index,
+        &job.options.with_dump_regions(&resolved.dump_regions),
+        helpers,
+        deadline,
+        launch,
+      )
+      .await
+  }
+
+  /// Waits until a freshly launched PE has run the entry trampoline, which reads the entry state
+  /// area, or has stopped.
+  async fn wait_for_trampoline(
+    &self,
+    pe_index: u32,
+    launch: &Launch,
+    deadline: Option<Instant>,
+  ) -> Result<()> {
+    let device = &self.inner.device;
+    while let Some(es) = device.poll_pe(pe_index, deadline).await? {
+      if !es.code.is_running() {
+        break;
+      }
+      let perfctr = device.read_perf_counters(pe_index)?;
+      if perfctr.commits - launch.start_perfctr.commits >= ENTRY_TRAMPOLINE_INSNS as u64 {
+        break;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use fnv::FnvHashSet;
+
+  use super::*;
+  use crate::{
+    backend::{BackendOp, RecordingBackend},
+    emulator::{
+      tests::{link_counter_image, link_test_image, state, test_device},
+      Emulator, EmulatorConfig,
+    },
+  };
+
+  fn job(image: &Arc<Image>, state_yaml: &str) -> Job {
+    Job {
+      image: image.clone(),
+      state: state(state_yaml),
+      options: RunOptions::default(),
+    }
+  }
+
+  /// Runs `jobs` and returns the results in the order they completed.
+  async fn run<B: Backend>(scheduler: &Scheduler<B>, jobs: Vec<Job>) -> Vec<JobResult> {
+    let mut rx = scheduler.run_batch(jobs).unwrap();
+    let mut results = vec![];
+    while let Some(x) = rx.recv().await {
+      results.push(x);
+    }
+    results
+  }
+
+  fn out(result: &JobResult) -> u64 {
+    let data = &result.result.as_ref().unwrap().dm_snapshots[0].data;
+    u64::from_le_bytes(data[..].try_into().unwrap())
+  }
+
+  const CORE_INDEX: &str = r#"
+buffers:
+  out: { fill: "00", size: 8 }
+registers: [0, { buffer: out }, 0, 0, 0, 0, 0, 0, 0, 0, { stack: 0x100 }]
+entryPoint: core_index
+dump: [{ buffer: out }]
+"#;
+
+  #[tokio::test]
+  async fn fails_without_pes() {
+    let config = EmulatorConfig {
+      num_pe: 0,
+      ..Default::default()
+    };
+    let device = Device::new(Arc::new(Emulator::new(config))).unwrap();
+    let image = Arc::new(link_test_image(Some(&["core_index"])));
+    assert!(Scheduler::new(device)
+      .run_batch(vec![job(&image, CORE_INDEX)])
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn prefers_jobs_with_resident_code() {
+    let backend = Arc::new(RecordingBackend::new(Emulator::new(
+      EmulatorConfig::default(),
+    )));
+    let scheduler = Scheduler::new(Device::new(backend.clone()).unwrap());
+    let a = Arc::new(link_test_image(Some(&["core_index"])));
+    let b = Arc::new(link_test_image(Some(&["core_index", "add"])));
+    assert_ne!(a.code, b.code);
+    let jobs = vec![
+      job(&a, CORE_INDEX),
+      job(&b, CORE_INDEX),
+      job(&a, CORE_INDEX),
+      job(&b, CORE_INDEX),
+    ];
+    let results = run(&scheduler, jobs).await;
+    let order = results.iter().map(|x| x.job_index).collect::<Vec<_>>();
+    assert_eq!(order, [0, 2, 1, 3]);
+    assert!(results.iter().all(|x| x.result.is_ok()));
+    let loads = backend
+      .ops()
+      .into_iter()
+      .filter(|x| matches!(x, BackendOp::LoadCode { .. }))
+      .count();
+    assert_eq!(loads, 2);
+  }
+
+  #[tokio::test]
+  async fn gives_each_job_fresh_data() {
+    let scheduler = Scheduler::new(test_device(2));
+    let image = Arc::new(link_counter_image());
+    let state = r#"
+buffers:
+  out: { fill: "00", size: 8 }
+registers: [0, { buffer: out }, 0, 0, 0, 0, 0, 0, 0, 0, { stack: 0x100 }]
+entryPoint: bump_counter
+dump: [{ buffer: out }]
+"#;
+    let jobs = (0..4).map(|_| job(&image, state)).collect();
+    let results = run(&scheduler, jobs).await;
+    assert_eq!(results.len(), 4);
+    for result in &results {
+      assert_eq!(out(result), 1, "job {}", result.job_index);
+    }
+  }
+
+  #[tokio::test]
+  async fn waits_for_trampoline_before_next_launch() {
+    // Every launch overwrites the entry state, so PEs only see their own arguments if no launch
+    // happens before the previous PE's trampoline has read them. Single-stepping the PEs makes
+    // the trampoline span many polls.
+    let config = EmulatorConfig {
+      num_pe: 4,
+      insns_per_poll: 1,
+      ..Default::default()
+    };
+    let scheduler = Scheduler::new(Device::new(Arc::new(Emulator::new(config))).unwrap());
+    let image = Arc::new(link_test_image(Some(&["add"])));
+    let jobs = (0..8)
+      .map(|i| {
+        let state = format!(
+          r#"
+buffers:
+  out: {{ fill: "00", size: 8 }}
+registers: [0, {}, 1000, {{ buffer: out }}, 0, 0, 0, 0, 0, 0, {{ stack: 0x100 }}]
+entryPoint: add
+dump: [{{ buffer: out }}]
+"#,
+          i
+        );
+        job(&image, &state)
+      })
+      .collect();
+    let results = run(&scheduler, jobs).await;
+    assert_eq!(results.len(), 8);
+    for result in &results {
+      assert_eq!(out(result), 1000 + result.job_index as u64);
+    }
+    let pes = results
+      .iter()
+      .map(|x| x.pe_index)
+      .collect::<FnvHashSet<_>>();
+    assert!(pes.len() > 1);
+  }
+}
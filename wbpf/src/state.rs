@@ -0,0 +1,460 @@
+//! Declarative description of the machine state a program is started with.
+
+use std::{
+  ops::Range,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  backend::Backend,
+  device::DmRegion,
+  dm::{DataMemory, DATA_MEMORY_SIZE},
+  dm_alloc::{DmAllocator, RESERVED_SIZE},
+  linker::image::Image,
+  types::FnvIndexMap,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineState {
+  /// Initial values of r0-r10.
+  pub registers: Vec<RegisterValue>,
+  pub entry_point: String,
+  /// Buffers written to data memory before the run, by name.
+  #[serde(default)]
+  pub buffers: FnvIndexMap<String, BufferSpec>,
+  /// Data memory to read back after the run.
+  #[serde(default)]
+  pub dump: Vec<DumpSpec>,
+  /// Directory relative `file` buffers are resolved against. Defaults to the current directory.
+  #[serde(skip)]
+  pub base_dir: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", untagged)]
+pub enum RegisterValue {
+  Value(i64),
+  /// Address of a buffer plus an optional byte offset.
+  Buffer {
+    buffer: String,
+    #[serde(default)]
+    offset: i64,
+  },
+  /// Top of a freshly allocated stack of the given size in bytes.
+  Stack {
+    stack: u32,
+  },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferSpec {
+  #[serde(flatten)]
+  pub init: BufferInit,
+  /// Size of the buffer. Defaults to the size of the initial contents, which are zero-padded if
+  /// shorter. Required for `fill`.
+  #[serde(default)]
+  pub size: Option<u32>,
+  /// Fixed data memory offset. Allocated after the image's data if absent.
+  #[serde(default)]
+  pub offset: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum BufferInit {
+  Hex(String),
+  Base64(String),
+  File(PathBuf),
+  /// Hex byte pattern repeated over the whole buffer.
+  Fill(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", untagged)]
+pub enum DumpSpec {
+  Buffer { buffer: String },
+  Region(DmRegion),
+}
+
+/// A `MachineState` with buffers placed in data memory.
+#[derive(Debug, Clone)]
+pub struct ResolvedState {
+  pub registers: [u64; 11],
+  pub dump_regions: Vec<DmRegion>,
+  /// Where the buffers were placed, by name.
+  pub buffers: Vec<DmRegion>,
+}
+
+impl MachineState {
+  /// Reads a state spec from a YAML or JSON file, resolving relative buffer files against the
+  /// file's directory.
+  pub fn from_file(path: &Path) -> Result<Self> {
+    let mut state: MachineState = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+    state.base_dir = path.parent().map(|x| x.to_path_buf());
+    Ok(state)
+  }
+
+  /// Writes the buffers to data memory, allocating them from `alloc`, and resolves the registers
+  /// and dump list against them. Buffers with a fixed offset must not overlap the entry state
+  /// area, the data of `image`, other buffers or memory already handed out by `alloc`.
+  pub fn resolve<B: Backend>(
+    &self,
+    dm: &DataMemory<B>,
+    image: &Image,
+    alloc: &mut DmAllocator,
+  ) -> Result<ResolvedState> {
+    if self.registers.len() != 11 {
+      anyhow::bail!(
+        "invalid state: expected 11 registers, got {}",
+        self.registers.len()
+      );
+    }
+
+    let contents = self
+      .buffers
+      .iter()
+      .map(|(name, spec)| {
+        spec
+          .contents(self.base_dir.as_deref())
+          .map_err(|e| anyhow::anyhow!("buffer {}: {}", name, e))
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    // Place the fixed buffers first so that allocations go around them.
+    let mut used = used_ranges(image);
+    let allocated = alloc.allocated();
+    used.push((
+      "allocated memory".into(),
+      allocated.start as u64..allocated.end as u64,
+    ));
+    let mut fixed = FnvHashMap::default();
+    for ((name, spec), data) in self.buffers.iter().zip(&contents) {
+      let offset = match spec.offset {
+        Some(x) => x,
+        None => continue,
+      };
+      let range = offset as u64..offset as u64 + data.len() as u64;
+      if range.end > DATA_MEMORY_SIZE as u64 {
+        anyhow::bail!(
+          "buffer {} at {:#x}..{:#x} runs past the end of data memory",
+          name,
+          range.start,
+          range.end
+        );
+      }
+      if let Some((other, x)) = used
+        .iter()
+        .find(|(_, x)| x.start < range.end && range.start < x.end)
+      {
+        anyhow::bail!(
+          "buffer {} at {:#x}..{:#x} overlaps {} at {:#x}..{:#x}",
+          name,
+          range.start,
+          range.end,
+          other,
+          x.start,
+          x.end
+        );
+      }
+      alloc.reserve(offset..range.end as u32);
+      used.push((format!("buffer {}", name), range));
+      fixed.insert(name.as_str(), offset);
+    }
+
+    let mut regions: FnvIndexMap<&str, DmRegion> = FnvIndexMap::default();
+    for ((name, _), data) in self.buffers.iter().zip(&contents) {
+      let region = match fixed.get(name.as_str()) {
+        Some(&offset) => DmRegion {
+          offset,
+          size: data.len() as u32,
+          name: None,
+        },
+        None => alloc.alloc(data.len() as u32, 8)?,
+      };
+      dm.do_dma_write(region.offset, data)?;
+      log::debug!(
+        "buffer {} at offset {:#x} size {}",
+        name,
+        region.offset,
+        region.size
+      );
+      regions.insert(name.as_str(), region);
+    }
+    let lookup = |name: &str| {
+      regions
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("undefined buffer {}", name))
+    };
+
+    let mut registers = [0u64; 11];
+    for (value, reg) in self.registers.iter().zip(registers.iter_mut()) {
+      *reg = match value {
+        RegisterValue::Value(x) => *x as u64,
+        RegisterValue::Buffer { buffer, offset } => {
+          (lookup(buffer)?.offset as i64).wrapping_add(*offset) as u64
+        }
+        RegisterValue::Stack { stack } => alloc.alloc_stack(*stack)? as u64,
+      };
+    }
+
+    let dump_regions = self
+      .dump
+      .iter()
+      .map(|x| match x {
+        DumpSpec::Buffer { buffer } => Ok(DmRegion {
+          name: Some(buffer.clone()),
+          ..lookup(buffer)?
+        }),
+        DumpSpec::Region(region) => Ok(region.clone()),
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let buffers = regions
+      .into_iter()
+      .map(|(name, region)| DmRegion {
+        name: Some(name.to_string()),
+        ..region
+      })
+      .collect();
+    Ok(ResolvedState {
+      registers,
+      dump_regions,
+      buffers,
+    })
+  }
+}
+
+impl ResolvedState {
+  /// Checks that the stack below r10 has room for the worst-case stack depth of `entry_point`
+  /// without running into the entry state area, the image's data or the buffers.
+  pub fn check_stack(&self, image: &Image, entry_point: &str) -> Result<()> {
+    let depth = match image
+      .offset_table
+      .as_ref()
+      .and_then(|x| x.max_stack_depths.get(entry_point))
+    {
+      Some(&x) if x >= 0 => x as u64,
+      Some(_) => {
+        log::warn!(
+          "stack depth of {} is unbounded, not checking the stack",
+          entry_point
+        );
+        return Ok(());
+      }
+      None => return Ok(()),
+    };
+    let size = DATA_MEMORY_SIZE as u64;
+    if depth > size {
+      anyhow::bail!(
+        "stack depth of {} is {} bytes, larger than data memory",
+        entry_point,
+        depth
+      );
+    }
+
+    // Addresses wrap around data memory, and so may the stack.
+    let top = (self.registers[10].wrapping_sub(1) % size) + 1;
+    let mut stack = Vec::with_capacity(2);
+    stack.push(top.saturating_sub(depth)..top);
+    if depth > top {
+      stack.push(size - (depth - top)..size);
+    }
+
+    let mut used = used_ranges(image);
+    for buffer in &self.buffers {
+      let start = buffer.offset as u64;
+      used.push((
+        format!("buffer {}", buffer.name.as_deref().unwrap_or("")),
+        start..start + buffer.size as u64,
+      ));
+    }
+
+    for (name, range) in &used {
+      if stack
+        .iter()
+        .any(|x| x.start < range.end && range.start < x.end)
+      {
+        anyhow::bail!(
+          "stack of {} bytes below r10 = {:#x} overlaps {} at {:#x}..{:#x}",
+          depth,
+          self.registers[10],
+          name,
+          range.start,
+          range.end
+        );
+      }
+    }
+    Ok(())
+  }
+}
+
+impl BufferSpec {
+  /// Returns the initial contents of the buffer, padded to its size.
+  fn contents(&self, base_dir: Option<&Path>) -> Result<Vec<u8>> {
+    let mut data = match &self.init {
+      BufferInit::Hex(x) => hex::decode(strip_whitespace(x))?,
+      BufferInit::Base64(x) => base64::decode(strip_whitespace(x))?,
+      BufferInit::File(path) => {
+        let path = match base_dir {
+          Some(base_dir) => base_dir.join(path),
+          None => path.clone(),
+        };
+        std::fs::read(&path)
+          .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?
+      }
+      BufferInit::Fill(pattern) => {
+        let pattern = hex::decode(strip_whitespace(pattern))?;
+        let size = self
+          .size
+          .ok_or_else(|| anyhow::anyhow!("fill requires a size"))? as usize;
+        if pattern.is_empty() {
+          anyhow::bail!("empty fill pattern");
+        }
+        pattern.iter().copied().cycle().take(size).collect()
+      }
+    };
+    if let Some(size) = self.size {
+      if data.len() > size as usize {
+        anyhow::bail!(
+          "contents are {} bytes, larger than size {}",
+          data.len(),
+          size
+        );
+      }
+      data.resize(size as usize, 0);
+    }
+    Ok(data)
+  }
+}
+
+/// Data memory used by the entry state and the data of `image`, by description.
+fn used_ranges(image: &Image) -> Vec<(String, Range<u64>)> {
+  let mut used: Vec<(String, Range<u64>)> = vec![("entry state".into(), 0..RESERVED_SIZE as u64)];
+  if let Some(platform) = &image.platform {
+    let start = platform.data_offset as u64;
+    used.push((
+      "image data".into(),
+      start..start + image.data.len() as u64 + image.bss_size as u64,
+    ));
+  }
+  used
+}
+
+fn strip_whitespace(s: &str) -> String {
+  s.chars().filter(|x| !x.is_whitespace()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulator::tests::{link_counter_image, state, test_device};
+
+  /// Resolves `yaml` for the counter image, whose data is at 0x100..0x108.
+  async fn resolve(yaml: &str, alloc: &mut DmAllocator) -> Result<ResolvedState> {
+    let image = link_counter_image();
+    let dm = test_device(1).data_memory().await?;
+    state(yaml).resolve(&dm, &image, alloc)
+  }
+
+  async fn resolve_error(buffers: &str) -> String {
+    let yaml = format!(
+      "buffers: {}\nregisters: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]\nentryPoint: bump_counter\n",
+      buffers
+    );
+    let mut alloc = DmAllocator::for_image(&link_counter_image()).unwrap();
+    resolve(&yaml, &mut alloc).await.unwrap_err().to_string()
+  }
+
+  #[tokio::test]
+  async fn places_allocations_around_fixed_buffers() {
+    let mut alloc = DmAllocator::for_image(&link_counter_image()).unwrap();
+    let resolved = resolve(
+      r#"
+buffers:
+  a: { hex: "01", size: 8 }
+  b: { hex: "02", size: 8, offset: 0x108 }
+registers: [0, { buffer: a }, { buffer: b, offset: 4 }, 0, 0, 0, 0, 0, 0, 0, { stack: 0x10 }]
+entryPoint: bump_counter
+"#,
+      &mut alloc,
+    )
+    .await
+    .unwrap();
+    assert_eq!(resolved.registers[1], 0x110);
+    assert_eq!(resolved.registers[2], 0x10c);
+    assert_eq!(resolved.registers[10], 0x128);
+    let buffers = resolved
+      .buffers
+      .iter()
+      .map(|x| (x.name.as_deref().unwrap(), x.offset))
+      .collect::<Vec<_>>();
+    assert_eq!(buffers, [("a", 0x110), ("b", 0x108)]);
+  }
+
+  #[tokio::test]
+  async fn rejects_fixed_buffer_over_entry_state() {
+    let e = resolve_error("{ x: { fill: '00', size: 8, offset: 0xf8 } }").await;
+    assert_eq!(
+      e,
+      "buffer x at 0xf8..0x100 overlaps entry state at 0x0..0x100"
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_fixed_buffer_over_image_data() {
+    let e = resolve_error("{ x: { fill: '00', size: 8, offset: 0x104 } }").await;
+    assert_eq!(
+      e,
+      "buffer x at 0x104..0x10c overlaps image data at 0x100..0x108"
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_overlapping_fixed_buffers() {
+    let e = resolve_error(
+      "{ x: { fill: '00', size: 16, offset: 0x200 }, y: { fill: '00', size: 8, offset: 0x208 } }",
+    )
+    .await;
+    assert_eq!(
+      e,
+      "buffer y at 0x208..0x210 overlaps buffer x at 0x200..0x210"
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_fixed_buffer_past_end_of_data_memory() {
+    let e = resolve_error("{ x: { fill: '00', size: 16, offset: 0xfff8 } }").await;
+    assert_eq!(
+      e,
+      "buffer x at 0xfff8..0x10008 runs past the end of data memory"
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_fixed_buffer_over_allocated_memory() {
+    let mut alloc = DmAllocator::for_image(&link_counter_image()).unwrap();
+    alloc.alloc(0x20, 8).unwrap();
+    let e = resolve(
+      r#"
+buffers:
+  x: { fill: "00", size: 8, offset: 0x120 }
+registers: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+entryPoint: bump_counter
+"#,
+      &mut alloc,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+      e.to_string(),
+      "buffer x at 0x120..0x128 overlaps allocated memory at 0x108..0x128"
+    );
+  }
+}
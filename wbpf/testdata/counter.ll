@@ -0,0 +1,22 @@
+; A program that writes to a global, used by the scheduler tests.
+;
+; Regenerate counter.o with:
+;   llc -march=bpf -filetype=obj counter.ll -o counter.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+declare void @wbpf_host_complete() noreturn
+
+@counter = global i64 0, align 8
+
+define void @bump_counter(i64* %out) #0 {
+  %v = load volatile i64, i64* @counter, align 8
+  %v2 = add i64 %v, 1
+  store volatile i64 %v2, i64* @counter, align 8
+  store i64 %v2, i64* %out, align 8
+  call void @wbpf_host_complete()
+  unreachable
+}
+
+attributes #0 = { noreturn nounwind }
@@ -0,0 +1,42 @@
+; Programs run by the emulator, scheduler and device tests.
+;
+; Regenerate emulator.o with:
+;   llc -march=bpf -filetype=obj emulator.ll -o emulator.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+declare void @wbpf_host_complete() noreturn
+declare i64 @wbpf_machine_get_core_index()
+declare i64 @host_add(i64, i64)
+
+; programs/simple/add.c
+define void @add(i32 %a, i32 %b, i32* %out) #0 {
+  %s = add i32 %a, %b
+  store i32 %s, i32* %out, align 4
+  call void @wbpf_host_complete()
+  unreachable
+}
+
+define void @core_index(i64* %out) #0 {
+  %c = call i64 @wbpf_machine_get_core_index()
+  store i64 %c, i64* %out, align 8
+  call void @wbpf_host_complete()
+  unreachable
+}
+
+define void @load(i64* %p, i64* %out) #0 {
+  %v = load volatile i64, i64* %p, align 8
+  store i64 %v, i64* %out, align 8
+  call void @wbpf_host_complete()
+  unreachable
+}
+
+define void @call_host(i64 %a, i64 %b, i64* %out) #0 {
+  %r = call i64 @host_add(i64 %a, i64 %b)
+  store i64 %r, i64* %out, align 8
+  call void @wbpf_host_complete()
+  unreachable
+}
+
+attributes #0 = { noreturn nounwind }
@@ -0,0 +1,26 @@
+; Two identical static functions for the identical code folding tests.
+;
+; Regenerate icf.o with:
+;   llc -march=bpf -filetype=obj icf.ll -o icf.o
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+define internal i64 @double_a(i64 %x) #0 {
+  %r = shl i64 %x, 1
+  ret i64 %r
+}
+
+define internal i64 @double_b(i64 %x) #0 {
+  %r = shl i64 %x, 1
+  ret i64 %r
+}
+
+define i64 @quadruple(i64 %x) #1 {
+  %a = call i64 @double_a(i64 %x)
+  %b = call i64 @double_b(i64 %a)
+  ret i64 %b
+}
+
+attributes #0 = { noinline nounwind }
+attributes #1 = { nounwind }
@@ -2,20 +2,30 @@ use std::{
   fs::{File, OpenOptions},
   io::{stdin, stdout, Read, Write},
   path::{Path, PathBuf},
+  str::FromStr,
+  sync::Arc,
+  time::Duration,
 };
 
 use anyhow::Result;
 use prost::Message;
+use serde::Serialize;
 use structopt::StructOpt;
-use tokio::runtime::Handle;
 use wbpf::{
-  device::{Device, MachineState},
+  backend::Backend,
+  device::{Device, DmRegion, MachineState, RunOptions, RunResult},
+  emulator::{Emulator, EmulatorConfig},
   linker::{
-    fs::link_files,
+    elf_writer::image_to_elf,
+    exports::parse_export_list,
+    fs::link_files_with_map,
     global_linker::GlobalLinkerConfig,
     image::{HostPlatform, Image, TargetMachine},
     image_disassembler::DisassembledImage,
+    layout::FunctionLayout,
+    symbolizer::Symbolizer,
   },
+  scheduler::{Job, Scheduler},
 };
 
 #[derive(Debug, StructOpt)]
@@ -25,6 +35,14 @@ struct Opt {
   #[structopt(long, short = "d")]
   device: Option<PathBuf>,
 
+  /// Use the software emulator instead of a device.
+  #[structopt(long)]
+  emulator: bool,
+
+  /// Number of processing elements of the emulator.
+  #[structopt(long, default_value = "1")]
+  emulator_num_pe: u32,
+
   #[structopt(subcommand)]
   cmd: Command,
 }
@@ -106,13 +124,17 @@ enum Command {
 
   /// Link.
   Link {
-    /// Input list.
+    /// Input objects and static archives.
     input: Vec<PathBuf>,
 
     /// Output path.
     #[structopt(long, short = "o")]
     output: Option<PathBuf>,
 
+    /// Also write the linked image as an ELF executable to this path.
+    #[structopt(long)]
+    elf: Option<PathBuf>,
+
     /// Target machine YAML/JSON config.
     #[structopt(long)]
     target_machine: Option<PathBuf>,
@@ -124,6 +146,38 @@ enum Command {
     /// Comma-delimited dead code elimination root functions.
     #[structopt(long)]
     dce_roots: Option<String>,
+
+    /// Write a map of the linked image to this path.
+    #[structopt(long)]
+    map: Option<PathBuf>,
+
+    /// Map format: text, json or yaml.
+    #[structopt(long, default_value = "text")]
+    map_format: OutputFormat,
+
+    /// Function layout: input, call-graph or pettis-hansen.
+    #[structopt(long, default_value = "input")]
+    layout: FunctionLayout,
+
+    /// YAML/JSON list of `{caller, callee, count}` call counts to weight the layout with.
+    #[structopt(long)]
+    call_profile: Option<PathBuf>,
+
+    /// Merge identical functions.
+    #[structopt(long)]
+    icf: bool,
+
+    /// Export list: one function per line, optionally followed by its argument count.
+    #[structopt(long)]
+    exports: Option<PathBuf>,
+
+    /// Leave functions that are not exported out of the offset table.
+    #[structopt(long)]
+    strip_unexported: bool,
+
+    /// Cache the analysis of each input object in this directory.
+    #[structopt(long)]
+    cache_dir: Option<PathBuf>,
   },
 
   /// Run image.
@@ -139,6 +193,41 @@ enum Command {
     /// Path to machine state spec.
     #[structopt(long)]
     state: PathBuf,
+
+    /// Data memory region to dump after the run, as `offset:size`. May be repeated.
+    #[structopt(long, parse(try_from_str = parse_dm_region))]
+    dump: Vec<DmRegion>,
+
+    /// Stop the PE and fail if the run takes longer than this many milliseconds.
+    #[structopt(long)]
+    timeout_ms: Option<u64>,
+
+    /// Stop the PE and fail if the program runs for more than this many cycles.
+    #[structopt(long)]
+    cycle_budget: Option<u64>,
+
+    /// Output format: text, json or yaml.
+    #[structopt(long, default_value = "text")]
+    format: OutputFormat,
+  },
+
+  /// Run an image once per machine state, spreading the runs across all PEs.
+  RunBatch {
+    /// Input file.
+    #[structopt(long, short = "i")]
+    input: PathBuf,
+
+    /// Path to machine state spec. May be repeated; each one is a job.
+    #[structopt(long, required = true)]
+    state: Vec<PathBuf>,
+
+    /// Stop a PE and fail its job if it takes longer than this many milliseconds.
+    #[structopt(long)]
+    timeout_ms: Option<u64>,
+
+    /// Output format: text, json (one object per line) or yaml.
+    #[structopt(long, default_value = "text")]
+    format: OutputFormat,
   },
 
   /// Disassemble image.
@@ -151,6 +240,37 @@ enum Command {
     #[structopt(long)]
     binary: bool,
   },
+
+  /// Resolve a code offset to its function and source object.
+  Symbolize {
+    /// Input file.
+    #[structopt(long, short = "i")]
+    input: PathBuf,
+
+    /// Code offset in bytes, decimal or 0x-prefixed hex.
+    #[structopt(long, parse(try_from_str = parse_u32))]
+    pc: u32,
+  },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+  Text,
+  Json,
+  Yaml,
+}
+
+impl FromStr for OutputFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "text" => Ok(OutputFormat::Text),
+      "json" => Ok(OutputFormat::Json),
+      "yaml" => Ok(OutputFormat::Yaml),
+      _ => Err(anyhow::anyhow!("unknown output format: {}", s)),
+    }
+  }
 }
 
 #[tokio::main]
@@ -158,25 +278,117 @@ async fn main() -> Result<()> {
   pretty_env_logger::init_timed();
   let opt = Opt::from_args();
 
-  let open_device = || {
-    if let Some(device) = &opt.device {
-      tokio::task::block_in_place(move || {
-        Handle::current()
-          .block_on(async move { Device::open(&device).await.map_err(anyhow::Error::from) })
-      })
-    } else {
-      Err(anyhow::anyhow!("no device specified"))
+  match opt.cmd {
+    Command::Link {
+      input,
+      output,
+      elf,
+      target_machine,
+      host_platform,
+      dce_roots,
+      map,
+      map_format,
+      layout,
+      call_profile,
+      icf,
+      exports,
+      strip_unexported,
+      cache_dir,
+    } => {
+      let target_machine: TargetMachine = if let Some(p) = &target_machine {
+        serde_yaml::from_str(&std::fs::read_to_string(p)?)?
+      } else {
+        Default::default()
+      };
+      let host_platform: HostPlatform = if let Some(p) = &host_platform {
+        serde_yaml::from_str(&std::fs::read_to_string(p)?)?
+      } else {
+        Default::default()
+      };
+      let config = GlobalLinkerConfig {
+        target_machine,
+        host_platform,
+        dce_roots: dce_roots.map(|x| x.split(',').map(|x| x.to_string()).collect()),
+        layout,
+        call_profile: if let Some(p) = &call_profile {
+          serde_yaml::from_str(&std::fs::read_to_string(p)?)?
+        } else {
+          vec![]
+        },
+        identical_code_folding: icf,
+        exports: if let Some(p) = &exports {
+          parse_export_list(&std::fs::read_to_string(p)?)?
+        } else {
+          vec![]
+        },
+        strip_unexported,
+        cache_dir,
+      };
+      let (image, link_map) = link_files_with_map(config, &input)?;
+      if let Some(p) = &output {
+        let mut output = open_output(p)?;
+        output.write_all(&image.encode_to_vec())?;
+      }
+      if let Some(p) = &elf {
+        let mut output = open_output(p)?;
+        output.write_all(&image_to_elf(&image)?)?;
+      }
+      if let Some(p) = &map {
+        let mut output = open_output(p)?;
+        match map_format {
+          OutputFormat::Text => write!(output, "{}", link_map)?,
+          OutputFormat::Json => writeln!(output, "{}", serde_json::to_string_pretty(&link_map)?)?,
+          OutputFormat::Yaml => write!(output, "{}", serde_yaml::to_string(&link_map)?)?,
+        }
+      }
     }
-  };
+    Command::DisassembleImage { input, binary } => {
+      let image = read_input(&input)?;
+      let image = Image::decode(image.as_slice())?;
+      if binary {
+        for (i, byte) in image.code.iter().enumerate() {
+          if i + 1 == image.code.len() {
+            println!("0x{:02x}", byte);
+          } else {
+            print!("0x{:02x}, ", byte);
+          }
+        }
+      } else {
+        println!("{}", DisassembledImage::new(&image));
+      }
+    }
+    Command::Symbolize { input, pc } => {
+      let image = read_input(&input)?;
+      let image = Image::decode(image.as_slice())?;
+      print!("{}", Symbolizer::new(&image).report(pc));
+    }
+    cmd => {
+      if opt.emulator {
+        let config = EmulatorConfig {
+          num_pe: opt.emulator_num_pe,
+          ..Default::default()
+        };
+        let device = Device::new(Arc::new(Emulator::new(config)))?;
+        run_device_command(device, cmd).await?;
+      } else if let Some(path) = &opt.device {
+        run_device_command(Device::open(path).await?, cmd).await?;
+      } else {
+        anyhow::bail!("no device specified");
+      }
+    }
+  }
 
-  match opt.cmd {
+  Ok(())
+}
+
+async fn run_device_command<B: Backend>(device: Device<B>, cmd: Command) -> Result<()> {
+  match cmd {
     Command::DmRead {
       output,
       offset,
       size,
       dma,
     } => {
-      let device = open_device()?;
       let mut f = open_output(&output)?;
       let device_dm = device.data_memory().await?;
       let mut buffer = vec![0u8; size as usize];
@@ -190,9 +402,7 @@ async fn main() -> Result<()> {
       f.write_all(&buffer)?;
       log::info!("Read {} bytes from data memory.", buffer.len());
     }
-
     Command::DmWrite { input, offset, dma } => {
-      let device = open_device()?;
       let buf = read_input(&input)?;
       let device_dm = device.data_memory().await?;
 
@@ -204,91 +414,157 @@ async fn main() -> Result<()> {
 
       log::info!("Wrote {} bytes to data memory.", buf.len());
     }
-
     Command::LoadCode {
       input,
       pe_index,
       offset,
     } => {
-      let device = open_device()?;
       let code = read_input(&input)?;
       device.load_code(pe_index, offset, &code)?;
       log::info!("Code loaded. See dmesg.");
     }
     Command::Stop { pe_index } => {
-      let device = open_device()?;
       device.stop(pe_index)?;
       log::info!("Stopped.");
     }
     Command::Start { pe_index, pc } => {
-      let device = open_device()?;
       device.start(pe_index, pc)?;
       log::info!("Started.");
     }
     Command::PerfCounters { pe_index } => {
-      let device = open_device()?;
       let perfctr = device.read_perf_counters(pe_index)?;
       println!("{:?}", perfctr);
     }
-    Command::Link {
-      input,
-      output,
-      target_machine,
-      host_platform,
-      dce_roots,
-    } => {
-      let target_machine: TargetMachine = if let Some(p) = &target_machine {
-        serde_yaml::from_str(&std::fs::read_to_string(p)?)?
-      } else {
-        Default::default()
-      };
-      let host_platform: HostPlatform = if let Some(p) = &host_platform {
-        serde_yaml::from_str(&std::fs::read_to_string(p)?)?
-      } else {
-        Default::default()
-      };
-      let config = GlobalLinkerConfig {
-        target_machine,
-        host_platform,
-        dce_roots: dce_roots.map(|x| x.split(',').map(|x| x.to_string()).collect()),
-      };
-      let image = link_files(config, &input)?;
-      if let Some(p) = &output {
-        let mut output = open_output(p)?;
-        output.write_all(&image.encode_to_vec())?;
-      }
-    }
     Command::Run {
       input,
       pe_index,
       state,
+      dump,
+      timeout_ms,
+      cycle_budget,
+      format,
     } => {
-      let device = open_device()?;
       let image = read_input(&input)?;
       let image = Image::decode(image.as_slice())?;
-      let state: MachineState = serde_yaml::from_str(&std::fs::read_to_string(&state)?)?;
-      device.run(&image, &state, pe_index).await?;
+      let state = MachineState::from_file(&state)?;
+      let options = RunOptions {
+        dump_regions: dump,
+        timeout: timeout_ms.map(Duration::from_millis),
+        cycle_budget,
+      };
+      let result = device.run(&image, &state, pe_index, &options).await?;
+      match format {
+        OutputFormat::Text => print_run_result(&image, &result),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&result)?),
+      }
     }
-    Command::DisassembleImage { input, binary } => {
+    Command::RunBatch {
+      input,
+      state,
+      timeout_ms,
+      format,
+    } => {
       let image = read_input(&input)?;
-      let image = Image::decode(image.as_slice())?;
-      if binary {
-        for (i, byte) in image.code.iter().enumerate() {
-          if i + 1 == image.code.len() {
-            println!("0x{:02x}", byte);
-          } else {
-            print!("0x{:02x}, ", byte);
+      let image = Arc::new(Image::decode(image.as_slice())?);
+      let options = RunOptions {
+        timeout: timeout_ms.map(Duration::from_millis),
+        ..Default::default()
+      };
+      let mut jobs = vec![];
+      for path in &state {
+        jobs.push(Job {
+          image: image.clone(),
+          state: MachineState::from_file(path)?,
+          options: options.clone(),
+        });
+      }
+      let mut results = Scheduler::new(device).run_batch(jobs)?;
+      let mut failed = false;
+      while let Some(x) = results.recv().await {
+        failed |= x.result.is_err();
+        let output = BatchOutput {
+          job: state[x.job_index].to_string_lossy().into_owned(),
+          pe_index: x.pe_index,
+          result: x.result.as_ref().ok(),
+          error: x.result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        match format {
+          OutputFormat::Text => {
+            println!("== {}", output.job);
+            match &x.result {
+              Ok(result) => print_run_result(&image, result),
+              Err(e) => println!("error: {:#}", e),
+            }
           }
+          OutputFormat::Json => println!("{}", serde_json::to_string(&output)?),
+          OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&output)?),
         }
-      } else {
-        println!("{}", DisassembledImage::new(&image));
       }
+      if failed {
+        anyhow::bail!("some jobs failed");
+      }
+    }
+    Command::Link { .. } | Command::DisassembleImage { .. } | Command::Symbolize { .. } => {
+      unreachable!()
     }
   }
 
   Ok(())
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchOutput<'a> {
+  job: String,
+  pe_index: u32,
+  result: Option<&'a RunResult>,
+  error: Option<String>,
+}
+
+fn print_run_result(image: &Image, result: &RunResult) {
+  if result.exception.code.is_fault() {
+    println!("PE {} faulted: {}", result.pe_index, result.exception);
+    print!("{}", Symbolizer::new(image).report(result.exception.pc));
+  } else {
+    println!("PE {} stopped: {}", result.pe_index, result.exception);
+  }
+  if let Some(return_value) = result.return_value {
+    println!("r0={:#x}", return_value);
+  }
+  println!(
+    "cycles={} commits={} elapsed={:?}",
+    result.perf.cycles, result.perf.commits, result.elapsed
+  );
+  for snapshot in &result.dm_snapshots {
+    let name = snapshot.name.as_deref().unwrap_or("dm");
+    println!(
+      "{}[{:#x}]: {}",
+      name,
+      snapshot.offset,
+      hex::encode(&snapshot.data)
+    );
+  }
+}
+
+fn parse_dm_region(s: &str) -> Result<DmRegion> {
+  let (offset, size) = s
+    .split_once(':')
+    .ok_or_else(|| anyhow::anyhow!("expected offset:size, got {}", s))?;
+  Ok(DmRegion {
+    offset: parse_u32(offset)?,
+    size: parse_u32(size)?,
+    name: None,
+  })
+}
+
+fn parse_u32(s: &str) -> Result<u32> {
+  Ok(match s.strip_prefix("0x") {
+    Some(hex) => u32::from_str_radix(hex, 16)?,
+    None => s.parse()?,
+  })
+}
+
 fn read_input(input: &Path) -> Result<Vec<u8>> {
   let mut f: Box<dyn Read> = if input.to_string_lossy() == "-" {
     Box::new(stdin())